@@ -0,0 +1,8 @@
+// hooks `step_particles` calls for every live particle. an effect file's `wgsl` replaces this file (see
+// effects/default.toml), and any hook it leaves out falls back to the one here.
+// `t` is simulated seconds, `uniforms.time`.
+
+// acceleration, in screen units per tick per tick
+fn force(p: Particle, t: f32) -> vec3<f32> {
+    return vec3<f32>(0.0, 0.0, 0.0);
+}
@@ -0,0 +1,251 @@
+use crate::gfx_ctx::GraphicsContext;
+use crate::stats::StatsCollector;
+use crate::timing::{Stopwatch, Tick};
+use serde::Serialize;
+
+use std::path::{Path, PathBuf};
+
+/// Frames run before we start measuring, so pipeline warm-up and the pool filling up don't skew things.
+const WARMUP_FRAMES: u32 = 120;
+
+/// Every benchmark frame simulates exactly this long, so the workload is the same however fast
+/// (or slow) the machine is.
+const BENCH_DT: f32 = 1.0 / 60.0;
+
+/// What the "mouse" is doing on a given frame.
+pub struct ScriptedInput {
+    /// In clip space, like `State::mouse_pos`.
+    pub mouse_pos: [f32; 2],
+    pub mouse_down: bool,
+}
+
+/// The cursor traces a figure of eight every four seconds, held down for 1.5s out of every 2s,
+/// so particles are constantly being born and dying all over the screen.
+fn scripted_input(frame: u32) -> ScriptedInput {
+    let t = frame as f32 * BENCH_DT;
+    let angle = t * std::f32::consts::PI * 0.5;
+
+    ScriptedInput {
+        mouse_pos: [0.6 * angle.sin(), 0.4 * (2.0 * angle).sin()],
+        mouse_down: t % 2.0 < 1.5,
+    }
+}
+
+/// Runs the scripted workload for a fixed number of frames, collecting timings as it goes.
+pub struct Bench {
+    /// Frames to measure, on top of the warm-up.
+    frames: u32,
+    /// Frames run so far, warm-up included.
+    frame: u32,
+    out: Option<PathBuf>,
+    stopwatch: Stopwatch,
+    last_frame: f64,
+    /// Readback the last samples came from, see `StatsCollector::latest_frame`.
+    last_readback: u64,
+    frame_times_ms: Vec<f64>,
+    physics_ms: Vec<f64>,
+    emission_ms: Vec<f64>,
+    render_ms: Vec<f64>,
+    alive: Vec<u32>,
+}
+
+impl Bench {
+    /// The report goes to `out`, or stdout if there isn't one.
+    pub fn new(frames: u32, out: Option<PathBuf>) -> Self {
+        Bench {
+            frames,
+            frame: 0,
+            out,
+            stopwatch: Stopwatch::new(),
+            last_frame: 0.0,
+            last_readback: 0,
+            frame_times_ms: Vec::with_capacity(frames as usize),
+            physics_ms: Vec::new(),
+            emission_ms: Vec::new(),
+            render_ms: Vec::new(),
+            alive: Vec::new(),
+        }
+    }
+
+    /// Input for the frame about to be simulated.
+    pub fn input(&self) -> ScriptedInput {
+        scripted_input(self.frame)
+    }
+
+    /// One fixed step for the frame about to be simulated.
+    pub fn tick(&self) -> Tick {
+        Tick {
+            dt: BENCH_DT,
+            time_scale: 1.0,
+            time: self.frame as f32 * BENCH_DT,
+            steps: 1,
+        }
+    }
+
+    /// Call once per frame, after `stats` has been updated.
+    pub fn frame_done(&mut self, stats: &StatsCollector) {
+        let now = self.stopwatch.seconds();
+        let measuring = self.frame > WARMUP_FRAMES;
+
+        if measuring {
+            self.frame_times_ms.push((now - self.last_frame) * 1000.0);
+        }
+        self.last_frame = now;
+        self.frame += 1;
+
+        // readbacks don't arrive every frame, only sample the ones that are new
+        if measuring && stats.latest_frame() != self.last_readback {
+            let stats = &stats.stats;
+
+            if stats.gpu_timing {
+                self.physics_ms.push(stats.physics_ms as f64);
+                self.emission_ms.push(stats.emission_ms as f64);
+                self.render_ms.push(stats.render_ms as f64);
+            }
+            self.alive.push(stats.alive);
+        }
+        self.last_readback = stats.latest_frame();
+    }
+
+    pub fn finished(&self) -> bool {
+        self.frame_times_ms.len() as u32 >= self.frames
+    }
+
+    pub fn report(&self, gc: &GraphicsContext, capacity: u32) -> Report {
+        let info = &gc.adapter_info;
+
+        let gpu_ms = if self.physics_ms.is_empty() {
+            None
+        } else {
+            Some(GpuReport {
+                physics: Summary::new(&self.physics_ms),
+                emission: Summary::new(&self.emission_ms),
+                render: Summary::new(&self.render_ms),
+            })
+        };
+
+        let alive = self
+            .alive
+            .iter()
+            .map(|&alive| alive as f64)
+            .collect::<Vec<_>>();
+        let frame_time_ms = Summary::new(&self.frame_times_ms);
+
+        Report {
+            frames: self.frame_times_ms.len() as u32,
+            capacity,
+            width: gc.size.width,
+            height: gc.size.height,
+            sample_count: gc.sample_count,
+            adapter: AdapterReport {
+                name: info.name.clone(),
+                vendor: info.vendor,
+                device: info.device,
+                device_type: format!("{:?}", info.device_type),
+                backend: format!("{:?}", info.backend),
+            },
+            fps: if frame_time_ms.mean > 0.0 {
+                1000.0 / frame_time_ms.mean
+            } else {
+                0.0
+            },
+            frame_time_ms,
+            gpu_ms,
+            alive: Summary::new(&alive),
+        }
+    }
+
+    /// Writes `report` as JSON to wherever `new` was told to.
+    pub fn write_report(&self, report: &Report) -> std::io::Result<()> {
+        match &self.out {
+            Some(path) => write_json(report, std::fs::File::create(path)?, Some(path)),
+            None => {
+                let stdout = std::io::stdout();
+                write_json(report, stdout.lock(), None)
+            }
+        }
+    }
+}
+
+fn write_json(
+    report: &Report,
+    mut writer: impl std::io::Write,
+    path: Option<&Path>,
+) -> std::io::Result<()> {
+    serde_json::to_writer_pretty(&mut writer, report)?;
+    writeln!(writer)?;
+
+    if let Some(path) = path {
+        eprintln!("wrote benchmark report to {}", path.display());
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    /// Frames measured, not counting the warm-up.
+    pub frames: u32,
+    pub capacity: u32,
+    pub width: u32,
+    pub height: u32,
+    pub sample_count: u32,
+    pub adapter: AdapterReport,
+    /// Mean frames per second over the measured frames.
+    pub fps: f64,
+    pub frame_time_ms: Summary,
+    /// `None` when the adapter can't do timestamp queries.
+    pub gpu_ms: Option<GpuReport>,
+    pub alive: Summary,
+}
+
+/// The interesting bits of `wgpu::AdapterInfo`.
+#[derive(Debug, Serialize)]
+pub struct AdapterReport {
+    pub name: String,
+    pub vendor: usize,
+    pub device: usize,
+    pub device_type: String,
+    pub backend: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GpuReport {
+    pub physics: Summary,
+    pub emission: Summary,
+    pub render: Summary,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Summary {
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Summary {
+    fn new(samples: &[f64]) -> Self {
+        if samples.is_empty() {
+            return Summary::default();
+        }
+
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+        // nearest rank
+        let percentile = |p: f64| {
+            let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+            sorted[rank.clamp(1, sorted.len()) - 1]
+        };
+
+        Summary {
+            mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+            p50: percentile(50.0),
+            p90: percentile(90.0),
+            p99: percentile(99.0),
+            max: sorted[sorted.len() - 1],
+        }
+    }
+}
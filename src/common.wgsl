@@ -0,0 +1,44 @@
+// shared by compute.wgsl and frag_vert.wgsl, which `#include` it (see preprocess.rs).
+// PARTICLE_GROUP_SIZE, the workgroup sizes, REFERENCE_FPS and LIFETIME_PER_FRAME are defined by pipelines.rs, and
+// DEAD_COUNT by whoever includes this, since only the compute passes need it to be atomic.
+
+// these need to match the Rust structs in pipelines.rs, the tests there check them
+[[block]]
+struct HelperData {
+    maxParticles: u32;
+    // how many indices are on the dead list, briefly negative while `emit` overshoots
+    deadCount: DEAD_COUNT;
+};
+
+[[block]]
+struct Uniforms {
+    paused: u32;
+    mouse_down: u32;
+    mouse_pos_last: vec2<f32>;
+    dt: f32;   // seconds per step
+    time: f32; // simulated seconds
+    time_scale: f32; // 1.0 is real time, lower is slow motion
+};
+
+struct Particle {
+    pos : vec3<f32>;
+    vel : vec3<f32>;
+    col : vec4<f32>; // color+brightness
+    lifetime : f32;
+    max_lifetime : f32;
+    emitter : u32;
+};
+
+// indices of live particles, rebuilt every step by compute.wgsl for the render pass to draw
+[[block]]
+struct AliveList {
+    indices : [[stride(4)]] array<u32>;
+};
+
+[[group(1), binding(1)]] var<uniform> uniforms : Uniforms;
+
+// effect parameters (and the numbers that used to be hard-coded in the physics) are in units of
+// a 60Hz frame, this turns the (scaled) dt into that
+fn step_frames() -> f32 {
+    return uniforms.dt * uniforms.time_scale * REFERENCE_FPS;
+}
@@ -1,85 +1,116 @@
-[[block]]
-struct HelperData {
-    maxParticles: u32;
-};
-
-[[block]]
-struct Uniforms {
-    paused: u32;
-    mouse_down: u32;
-    mouse_pos_last: vec2<f32>;
-};
-
-struct Particle {
-    pos : vec3<f32>;
-    vel : vec3<f32>;
-    col : vec4<f32>; // color+brightness
-    lifetime : f32;
-};
-
-struct VertexOut {
-    [[location(0)]] pos: vec3<f32>;
-    [[location(1)]] col: vec3<f32>;
-};
-
-[[block]]
-struct Particles {
-    group : array<[[stride(64)]] array<Particle, 256>>;
-};
-
-[[group(0), binding(0)]] var<storage, read_write> particlesSrc : Particles;
-
-// should this be in bind_group 1?
-[[group(1), binding(0)]] var<storage, read_write> helperData : HelperData;
-[[group(1), binding(1)]] var<uniform> uniforms : Uniforms;
-
-
-[[stage(compute), workgroup_size(64, 1, 1)]]
+#include "compute_common.wgsl"
+#include "behaviour.wgsl"
+
+// empties the alive list, run before every `step_particles`
+[[stage(compute), workgroup_size(1)]]
+fn reset_alive_list() {
+    // paused steps leave the list as it was, so the frozen particles are still drawn
+    if (uniforms.paused == 1u) {
+        return;
+    }
+
+    atomicStore(&drawArgs.instance_count, 0u);
+}
+
+// a particle is dead once its lifetime runs out, zeroed (never used) particles included
+[[stage(compute), workgroup_size(STEP_WORKGROUP_SIZE, 1, 1)]]
 fn step_particles([[builtin(global_invocation_id)]] global_invocation_id: vec3<u32>) {
-    for(var y: i32 = 0; y < 256; y = y + 1) {
+    // the last workgroup can run past the end when the capacity isn't a whole number of workgroups
+    if (uniforms.paused == 1u || global_invocation_id.x >= helperData.maxParticles / PARTICLE_GROUP_SIZE) {
+        return;
+    }
+
+    let frames = step_frames();
+
+    for(var y: u32 = 0u; y < PARTICLE_GROUP_SIZE; y = y + 1u) {
         let particle: ptr<storage, Particle, read_write> = &particlesSrc.group[global_invocation_id.x][y];
 
-        if ((*particle).lifetime < 0.0) {
+        if ((*particle).lifetime <= 0.0) {
             continue;
         }
 
         // physic :)
-        (*particle).lifetime = (*particle).lifetime - 0.16;
-        (*particle).pos = (*particle).pos + (*particle).vel;
-        (*particle).vel = (*particle).vel * vec3<f32>(0.998); // friction
+        (*particle).lifetime = (*particle).lifetime - LIFETIME_PER_FRAME * frames;
+        (*particle).vel = (*particle).vel + force(*particle, uniforms.time) * frames;
+        (*particle).pos = (*particle).pos + (*particle).vel * frames;
+        // particles can outlive the effect that emitted them, see set_effect, so the emitter may be gone
+        let emitter = min((*particle).emitter, effect.emitter_count - 1u);
+        (*particle).vel = (*particle).vel * vec3<f32>(pow(effect.emitters[emitter].friction, frames));
+
+        let index = global_invocation_id.x * PARTICLE_GROUP_SIZE + y;
+        if ((*particle).lifetime <= 0.0) {
+            push_dead(index);
+        } else {
+            push_alive(index);
+        }
     }
 }
 
-[[stage(compute), workgroup_size(1)]]
+// one invocation per particle to emit, x is the particle and y the emitter.
+// `count` is per 60Hz frame, so a shorter step spawns proportionally fewer to keep the rate the same.
+[[stage(compute), workgroup_size(EMIT_WORKGROUP_SIZE, 1, 1)]]
 fn emit([[builtin(global_invocation_id)]] global_invocation_id: vec3<u32>) {
-    let total_to_add = 512;
-    var left_to_add = total_to_add;
+    let emitter_idx = global_invocation_id.y;
 
-    if (uniforms.mouse_down == 0u) {
+    if (uniforms.paused == 1u || uniforms.mouse_down == 0u || emitter_idx >= effect.emitter_count) {
         return;
     }
 
-    for(var group: i32 = 0; group < i32(helperData.maxParticles) / 256; group = group + 1) {
-        let particle: ptr<storage, Particle, read_write> = &particlesSrc.group[group][0];
+    let frames = step_frames();
+    let emitter = effect.emitters[emitter_idx];
+    let count = max(1u, u32(f32(emitter.count) * frames + 0.5));
+    let emitted = global_invocation_id.x;
 
-        if ((*particle).lifetime > 0.0) {
-            continue;
-        }
-        for (var p: i32 = 0; p < 256; p = p + 1) {
-            let particle: ptr<storage, Particle, read_write> = &particlesSrc.group[group][p];
+    if (emitted >= count) {
+        return;
+    }
+
+    // pop a dead particle. if the list ran dry, give back what we took and skip this one
+    let top = atomicSub(&helperData.deadCount, 1) - 1;
+    if (top < 0) {
+        // (no way to discard a call's result in this WGSL)
+        let restored = atomicAdd(&helperData.deadCount, 1);
+        return;
+    }
+
+    let index = deadList.indices[top];
+    let particle: ptr<storage, Particle, read_write> = &particlesSrc.group[index / PARTICLE_GROUP_SIZE][index % PARTICLE_GROUP_SIZE];
+
+    let t = f32(emitted) / f32(count);
+    var angle = 2.0*3.14159*t;
+    var speed = emitter.speed;
 
-            (*particle).lifetime = 600.0;
-            let x = cos(2.0*3.14159*(f32(left_to_add)/(f32(total_to_add))));
-            let y = sin(2.0*3.14159*(f32(left_to_add)/(f32(total_to_add))));
-            (*particle).pos = vec3<f32>(uniforms.mouse_pos_last, 0.5); // TODO: 3D transform mouse position based on camera
+    if (emitter.shape == SHAPE_DISC) {
+        // sunflower spiral: golden angle steps, sqrt keeps the density even
+        angle = f32(emitted) * 2.39996;
+        speed = speed * sqrt(t);
+    }
+
+    (*particle).lifetime = emitter.lifetime;
+    (*particle).max_lifetime = emitter.lifetime;
+    (*particle).emitter = emitter_idx;
+    (*particle).pos = vec3<f32>(uniforms.mouse_pos_last + emitter.offset, 0.5); // TODO: 3D transform mouse position based on camera
 
-            // TODO: explode particles based on mouse velocity normal?
-            (*particle).vel = vec3<f32>(x, y, 0.0) * vec3<f32>(0.002, 0.002, 0.0);
+    // TODO: explode particles based on mouse velocity normal?
+    (*particle).vel = vec3<f32>(cos(angle), sin(angle), 0.0) * vec3<f32>(speed, speed, 0.0);
+
+    push_alive(index);
+}
+
+// refills the dead and alive lists from scratch, for a fresh (or freshly resized) particle buffer.
+// expects `deadCount` and the instance count to have been reset to 0, and is laid out like `step_particles`
+[[stage(compute), workgroup_size(STEP_WORKGROUP_SIZE, 1, 1)]]
+fn rebuild_lists([[builtin(global_invocation_id)]] global_invocation_id: vec3<u32>) {
+    if (global_invocation_id.x >= helperData.maxParticles / PARTICLE_GROUP_SIZE) {
+        return;
+    }
 
-            left_to_add = left_to_add - 1;
-            if (left_to_add == 0) {
-                return;
-            }
+    for(var y: u32 = 0u; y < PARTICLE_GROUP_SIZE; y = y + 1u) {
+        let index = global_invocation_id.x * PARTICLE_GROUP_SIZE + y;
+        if (particlesSrc.group[global_invocation_id.x][y].lifetime <= 0.0) {
+            push_dead(index);
+        } else {
+            push_alive(index);
         }
     }
 }
@@ -0,0 +1,65 @@
+// what the compute passes bind, with helpers for the particle lists. compute.wgsl `#include`s it, and so can
+// extra stages (see stages.rs), which get the same bind groups 0 and 1.
+#define DEAD_COUNT atomic<i32>
+#include "common.wgsl"
+
+// like the ones in common.wgsl, these need to match the Rust structs in pipelines.rs
+
+// see effect.rs, one of these per emitter in the effect file
+let SHAPE_RING: u32 = 0u;
+let SHAPE_DISC: u32 = 1u;
+
+struct Emitter {
+    offset: vec2<f32>;
+    count: u32;
+    shape: u32;
+    lifetime: f32;
+    speed: f32;
+    friction: f32;
+};
+
+[[block]]
+struct Effect {
+    emitter_count: u32;
+    [[align(16)]] emitters: [[stride(32)]] array<Emitter, 16>;
+};
+
+[[block]]
+struct Particles {
+    group : array<[[stride(64)]] array<Particle, PARTICLE_GROUP_SIZE>>;
+};
+
+// indices of dead particles, a stack `step_particles` pushes onto and `emit` pops from
+[[block]]
+struct DeadList {
+    indices : [[stride(4)]] array<u32>;
+};
+
+// `DrawArgs` in pipelines.rs, one instance per live particle
+[[block]]
+struct DrawArgs {
+    vertex_count: u32;
+    instance_count: atomic<u32>;
+    base_vertex: u32;
+    base_instance: u32;
+};
+
+[[group(0), binding(0)]] var<storage, read_write> particlesSrc : Particles;
+[[group(0), binding(1)]] var<storage, read_write> deadList : DeadList;
+[[group(0), binding(2)]] var<storage, read_write> aliveList : AliveList;
+[[group(0), binding(3)]] var<storage, read_write> drawArgs : DrawArgs;
+
+// should this be in bind_group 1?
+[[group(1), binding(0)]] var<storage, read_write> helperData : HelperData;
+[[group(1), binding(2)]] var<uniform> effect : Effect;
+
+
+fn push_dead(index: u32) {
+    let top = atomicAdd(&helperData.deadCount, 1);
+    deadList.indices[top] = index;
+}
+
+fn push_alive(index: u32) {
+    let top = atomicAdd(&drawArgs.instance_count, 1u);
+    aliveList.indices[top] = index;
+}
@@ -0,0 +1,231 @@
+//! The simulation in compute.wgsl, redone in plain Rust on a `Vec<Particle>`.
+//!
+//! It's the reference the GPU results are checked against in the tests, and what runs instead of the
+//! compute passes on adapters that can't do compute (or with `--cpu-sim`). Keep it in step with the
+//! shaders: same rules, same constants, same order of passes.
+
+use crate::effect::Shape;
+use crate::pipelines::{EffectParams, Particle, Uniforms, LIFETIME_PER_FRAME, REFERENCE_FPS};
+use bytemuck::Zeroable;
+
+pub struct CpuSim {
+    particles: Vec<Particle>,
+    /// Indices of dead particles, `emit` pops them off the end.
+    dead: Vec<u32>,
+    /// Indices of the live particles, in the order they'd be drawn.
+    alive: Vec<u32>,
+}
+
+impl CpuSim {
+    /// A pool of `capacity` particles, all dead.
+    pub fn new(capacity: u32) -> Self {
+        let mut sim = CpuSim {
+            particles: Vec::new(),
+            dead: Vec::new(),
+            alive: Vec::new(),
+        };
+        sim.resize(capacity);
+        sim
+    }
+
+    pub fn particles(&self) -> &[Particle] {
+        &self.particles
+    }
+
+    /// What the GPU calls the alive list, its length is the instance count.
+    pub fn alive(&self) -> &[u32] {
+        &self.alive
+    }
+
+    /// Replaces the whole pool with `particles`, followed by dead ones to fill it up, like
+    /// `ParticleSystem::upload_particles`. Panics if there are more than fit.
+    pub fn upload(&mut self, particles: &[Particle]) {
+        self.particles.fill(Particle::zeroed());
+        self.particles[..particles.len()].copy_from_slice(particles);
+        self.rebuild_lists();
+    }
+
+    /// Keeps the first `capacity` particles (the rest start out dead), like `ParticleSystem::resize`.
+    pub fn resize(&mut self, capacity: u32) {
+        self.particles.resize(capacity as usize, Particle::zeroed());
+        self.rebuild_lists();
+    }
+
+    /// One step: what `reset_alive_list`, `step_particles` and then `emit` do on the GPU.
+    pub fn step(&mut self, uniforms: &Uniforms, effect: &EffectParams) {
+        // paused steps leave everything as it was, the alive list included
+        if uniforms.paused == 1 {
+            return;
+        }
+
+        let frames = uniforms.dt * uniforms.time_scale * REFERENCE_FPS;
+
+        self.alive.clear();
+        self.step_particles(effect, frames);
+
+        if uniforms.mouse_down == 1 {
+            self.emit(uniforms, effect, frames);
+        }
+    }
+
+    fn step_particles(&mut self, effect: &EffectParams, frames: f32) {
+        for (index, particle) in self.particles.iter_mut().enumerate() {
+            if particle.lifetime <= 0.0 {
+                continue;
+            }
+
+            // as in compute.wgsl, the emitter may belong to an effect that's since been swapped out
+            let emitter = particle.emitter.min(effect.emitter_count - 1);
+            let friction = effect.emitters[emitter as usize].friction.powf(frames);
+
+            // an effect's `force` is WGSL, so it only ever runs on the GPU (see behaviour.wgsl)
+            particle.lifetime -= LIFETIME_PER_FRAME * frames;
+            for axis in 0..3 {
+                particle.pos[axis] += particle.vel[axis] * frames;
+                particle.vel[axis] *= friction;
+            }
+
+            if particle.lifetime <= 0.0 {
+                self.dead.push(index as u32);
+            } else {
+                self.alive.push(index as u32);
+            }
+        }
+    }
+
+    // 3.14159 is what `emit` uses, not a mistake for `PI`
+    #[allow(clippy::approx_constant)]
+    fn emit(&mut self, uniforms: &Uniforms, effect: &EffectParams, frames: f32) {
+        let emitters = &effect.emitters[..effect.emitter_count as usize];
+
+        for (emitter_idx, emitter) in emitters.iter().enumerate() {
+            let count = ((emitter.count as f32 * frames + 0.5) as u32).max(1);
+
+            for emitted in 0..count {
+                // out of dead particles, the rest don't get emitted
+                let index = match self.dead.pop() {
+                    Some(index) => index,
+                    None => return,
+                };
+
+                // the same (approximate) constants as the shader, so the results match
+                let t = emitted as f32 / count as f32;
+                let (angle, speed) = if emitter.shape == Shape::Disc as u32 {
+                    (emitted as f32 * 2.39996, emitter.speed * t.sqrt())
+                } else {
+                    (2.0 * 3.14159 * t, emitter.speed)
+                };
+
+                let particle = &mut self.particles[index as usize];
+                particle.lifetime = emitter.lifetime;
+                particle.max_lifetime = emitter.lifetime;
+                particle.emitter = emitter_idx as u32;
+                particle.pos = [
+                    uniforms.mouse_pos_last[0] + emitter.offset[0],
+                    uniforms.mouse_pos_last[1] + emitter.offset[1],
+                    0.5,
+                ];
+                particle.vel = [angle.cos() * speed, angle.sin() * speed, 0.0];
+
+                self.alive.push(index);
+            }
+        }
+    }
+
+    /// What `rebuild_lists` does on the GPU, sorts every particle onto the dead or alive list.
+    fn rebuild_lists(&mut self) {
+        self.dead.clear();
+        self.alive.clear();
+
+        for (index, particle) in self.particles.iter().enumerate() {
+            if particle.lifetime <= 0.0 {
+                self.dead.push(index as u32);
+            } else {
+                self.alive.push(index as u32);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effect::{Effect, Emitter};
+
+    fn uniforms(mouse_down: bool) -> Uniforms {
+        Uniforms {
+            paused: 0,
+            mouse_down: mouse_down as u32,
+            mouse_pos_last: [0.0, 0.0],
+            dt: 1.0 / REFERENCE_FPS,
+            time: 0.0,
+            time_scale: 1.0,
+            _pad: 0,
+        }
+    }
+
+    fn effect(count: u32) -> EffectParams {
+        Effect {
+            emitters: vec![Emitter {
+                count,
+                ..Emitter::default()
+            }],
+            ..Effect::default()
+        }
+        .to_params()
+    }
+
+    #[test]
+    fn dead_particles_are_reused() {
+        let mut sim = CpuSim::new(16);
+        let effect = effect(4);
+
+        sim.step(&uniforms(true), &effect);
+        assert_eq!(sim.alive().len(), 4);
+
+        // age them out, then emit again: the pool never needs more than one burst
+        let lifetime = Emitter::default().lifetime;
+        let steps = (lifetime / LIFETIME_PER_FRAME) as usize + 2;
+        for _ in 0..steps {
+            sim.step(&uniforms(false), &effect);
+        }
+        assert!(sim.alive().is_empty());
+
+        sim.step(&uniforms(true), &effect);
+        assert_eq!(sim.alive().len(), 4);
+        assert_eq!(sim.dead.len(), 12);
+    }
+
+    #[test]
+    fn emit_stops_when_the_pool_is_full() {
+        let mut sim = CpuSim::new(16);
+        let effect = effect(10);
+
+        sim.step(&uniforms(true), &effect);
+        sim.step(&uniforms(true), &effect);
+
+        assert_eq!(sim.alive().len(), 16);
+        assert!(sim.dead.is_empty());
+        assert!(sim.particles().iter().all(|p| p.lifetime > 0.0));
+    }
+
+    #[test]
+    fn paused_steps_change_nothing() {
+        let mut sim = CpuSim::new(16);
+        let effect = effect(4);
+        sim.step(&uniforms(true), &effect);
+        let before = sim.particles().to_vec();
+
+        let paused = Uniforms {
+            paused: 1,
+            ..uniforms(true)
+        };
+        sim.step(&paused, &effect);
+
+        assert_eq!(
+            bytemuck::cast_slice::<_, u8>(sim.particles()),
+            bytemuck::cast_slice::<_, u8>(&before)
+        );
+        assert_eq!(sim.alive().len(), 4);
+    }
+}
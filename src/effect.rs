@@ -0,0 +1,386 @@
+use crate::pipelines::{EffectParams, EmitterParams, MAX_EMITTERS};
+use crate::shaders;
+use serde::Deserialize;
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// The effect that's built into the binary, used when no effect file is given (and always on the web).
+pub const DEFAULT_EFFECT: &str = include_str!("../effects/default.toml");
+
+/// A particle effect as authored in an effect file, see effects/default.toml for the format.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Effect {
+    /// How many particles the effect's system has room for, instead of `--particles`.
+    #[serde(default)]
+    pub capacity: Option<u32>,
+    /// An image to draw particles with instead of the built-in dot. Relative to the effect file once loaded.
+    #[serde(default)]
+    pub texture: Option<PathBuf>,
+    #[serde(default)]
+    pub blend: BlendMode,
+    /// WGSL hooks spliced into the physics, see behaviour.wgsl.
+    #[serde(default)]
+    pub wgsl: Option<Snippet>,
+    #[serde(rename = "emitter", default)]
+    pub emitters: Vec<Emitter>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Emitter {
+    pub count: u32,
+    pub shape: Shape,
+    pub speed: f32,
+    pub lifetime: f32,
+    pub friction: f32,
+    pub offset: [f32; 2],
+}
+
+impl Default for Emitter {
+    fn default() -> Self {
+        Emitter {
+            count: 512,
+            shape: Shape::Ring,
+            speed: 0.002,
+            lifetime: 600.0,
+            friction: 0.998,
+            offset: [0.0, 0.0],
+        }
+    }
+}
+
+/// How an emitter hands out initial velocities. Values match `SHAPE_*` in compute.wgsl.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Shape {
+    /// Evenly spaced directions, all at full speed.
+    Ring = 0,
+    /// Directions and speeds spread evenly over a disc.
+    Disc = 1,
+}
+
+/// WGSL from an effect file, compiled into `step_particles` in place of behaviour.wgsl.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "String")]
+pub struct Snippet {
+    pub source: String,
+    /// Line of the effect file the snippet starts on, for error messages. `None` if we couldn't tell,
+    /// which happens when it's written as a string with escapes in.
+    pub line: Option<usize>,
+}
+
+impl From<String> for Snippet {
+    fn from(source: String) -> Self {
+        Snippet { source, line: None }
+    }
+}
+
+impl Snippet {
+    /// The snippet, followed by the built-in hooks it doesn't define itself.
+    pub fn with_defaults(&self) -> String {
+        let mut source = self.source.clone();
+        if !defines_fn(&self.source, "force") {
+            // starting on a line of their own, so `describe_line` can tell them apart
+            if !source.is_empty() && !source.ends_with('\n') {
+                source.push('\n');
+            }
+            source.push_str(shaders::BEHAVIOUR_SHADER);
+        }
+        source
+    }
+
+    /// Where 1-based `line` of `with_defaults` is, to point errors at.
+    pub fn describe_line(&self, line: usize) -> String {
+        let own = self.source.lines().count();
+        match self.line {
+            _ if line > own => format!(
+                "built-in {} line {}, which the effect didn't replace",
+                shaders::BEHAVIOUR_SHADER_FILE,
+                line - own
+            ),
+            Some(first) => format!("effect line {}", first + line - 1),
+            None => format!(
+                "effect wgsl line {} (not a line of the file, it couldn't be found there)",
+                line
+            ),
+        }
+    }
+}
+
+/// Where the line with the `wgsl = ` key starts in an effect file, `None` if it isn't written plainly.
+fn wgsl_key(source: &str) -> Option<usize> {
+    let mut start = 0;
+    for line in source.split_inclusive('\n') {
+        if matches!(line.trim_start().strip_prefix("wgsl"), Some(rest) if rest.trim_start().starts_with('='))
+        {
+            return Some(start);
+        }
+        start += line.len();
+    }
+    None
+}
+
+/// Whether there's an `fn name` in `source`, give or take comments.
+fn defines_fn(source: &str, name: &str) -> bool {
+    let mut words = source
+        .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|word| !word.is_empty());
+
+    while let Some(word) = words.next() {
+        if word == "fn" && words.next() == Some(name) {
+            return true;
+        }
+    }
+    false
+}
+
+/// How particles are combined with what's already been drawn.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlendMode {
+    /// Overlapping particles add up and brighten, the original look.
+    #[default]
+    Additive = 0,
+    /// Particles cover what's behind them by their alpha, for smoke and the like.
+    Alpha = 1,
+}
+
+impl BlendMode {
+    /// Every mode, indexed by its value.
+    pub const ALL: [BlendMode; 2] = [BlendMode::Additive, BlendMode::Alpha];
+}
+
+#[derive(Debug)]
+pub enum EffectError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for EffectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EffectError::Io(e) => write!(f, "couldn't read effect file: {}", e),
+            EffectError::Parse(e) => write!(f, "couldn't parse effect file: {}", e),
+            EffectError::Invalid(reason) => write!(f, "invalid effect: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for EffectError {}
+
+impl Effect {
+    pub fn load(path: &Path) -> Result<Self, EffectError> {
+        let source = std::fs::read_to_string(path).map_err(EffectError::Io)?;
+        let mut effect = Self::parse(&source)?;
+        if let (Some(texture), Some(dir)) = (&mut effect.texture, path.parent()) {
+            *texture = dir.join(&*texture);
+        }
+        Ok(effect)
+    }
+
+    pub fn parse(source: &str) -> Result<Self, EffectError> {
+        let mut effect: Effect = toml::from_str(source).map_err(EffectError::Parse)?;
+        effect.validate()?;
+
+        // toml doesn't say where values were, but a literal string's text is in the file as it is,
+        // after its key (and not necessarily only there, it could be in a comment too)
+        if let Some(snippet) = &mut effect.wgsl {
+            snippet.line = wgsl_key(source).and_then(|key| {
+                source[key..]
+                    .find(snippet.source.as_str())
+                    .map(|start| source[..key + start].matches('\n').count() + 1)
+            });
+        }
+
+        Ok(effect)
+    }
+
+    fn validate(&self) -> Result<(), EffectError> {
+        if self.capacity == Some(0) {
+            return Err(EffectError::Invalid("capacity of 0".to_string()));
+        }
+
+        if self.emitters.is_empty() {
+            return Err(EffectError::Invalid(
+                "no emitters, it needs at least one [[emitter]]".to_string(),
+            ));
+        }
+        if self.emitters.len() > MAX_EMITTERS {
+            return Err(EffectError::Invalid(format!(
+                "{} emitters, but at most {} are supported",
+                self.emitters.len(),
+                MAX_EMITTERS
+            )));
+        }
+
+        for (i, emitter) in self.emitters.iter().enumerate() {
+            if emitter.count == 0 {
+                return Err(EffectError::Invalid(format!(
+                    "emitter {} has a count of 0",
+                    i
+                )));
+            }
+            if emitter.lifetime.is_nan() || emitter.lifetime <= 0.0 {
+                return Err(EffectError::Invalid(format!(
+                    "emitter {} needs a positive lifetime",
+                    i
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Packs the effect into what the compute shader reads.
+    pub fn to_params(&self) -> EffectParams {
+        let mut params = EffectParams {
+            emitter_count: self.emitters.len() as u32,
+            _pad: [0; 3],
+            emitters: [bytemuck::Zeroable::zeroed(); MAX_EMITTERS],
+        };
+
+        for (gpu, emitter) in params.emitters.iter_mut().zip(&self.emitters) {
+            *gpu = EmitterParams {
+                offset: emitter.offset,
+                count: emitter.count,
+                shape: emitter.shape as u32,
+                lifetime: emitter.lifetime,
+                speed: emitter.speed,
+                friction: emitter.friction,
+                _pad: 0,
+            };
+        }
+
+        params
+    }
+}
+
+impl Default for Effect {
+    fn default() -> Self {
+        Effect::parse(DEFAULT_EFFECT).expect("built-in effect is invalid")
+    }
+}
+
+/// A `force` hook pulling everything gently down, for the tests.
+#[cfg(test)]
+pub(crate) const FORCE: &str = concat!(
+    "fn force(p: Particle, t: f32) -> vec3<f32> {\n",
+    "    return vec3<f32>(0.0, -0.001, 0.0);\n",
+    "}\n",
+);
+
+/// An effect whose `force` is missing a `;`, which naga trips over on line 5.
+#[cfg(test)]
+pub(crate) const BROKEN_EFFECT: &str = concat!(
+    "# gravity, nearly\n",
+    "wgsl = '''\n",
+    "fn force(p: Particle, t: f32) -> vec3<f32> {\n",
+    "    return vec3<f32>(0.0, -1.0, 0.0)\n",
+    "}\n",
+    "'''\n",
+    "[[emitter]]\n",
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Effect {
+        Effect::parse(source).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn snippet(source: &str, line: Option<usize>) -> Snippet {
+        Snippet {
+            source: source.to_string(),
+            line,
+        }
+    }
+
+    #[test]
+    fn parse_finds_the_snippet_line() {
+        let effect = parse(&format!(
+            "# falls\nblend = \"alpha\"\nwgsl = '''\n{}'''\n\n[[emitter]]\n",
+            FORCE
+        ));
+        let wgsl = effect.wgsl.expect("no wgsl");
+        assert_eq!(wgsl.source, FORCE);
+        assert_eq!(wgsl.line, Some(4));
+    }
+
+    #[test]
+    fn parse_skips_copies_of_the_snippet_before_its_key() {
+        let line = "fn force(p: Particle, t: f32) -> vec3<f32> { return vec3<f32>(0.0); }";
+        let effect = parse(&format!(
+            "# was: {}\n# wgsl, but faster\nwgsl = '''\n{}'''\n[[emitter]]\n",
+            line, line
+        ));
+        assert_eq!(effect.wgsl.expect("no wgsl").line, Some(4));
+    }
+
+    #[test]
+    fn parse_gives_up_on_escaped_snippets() {
+        // the text is in the file with `\n`s rather than line breaks, so it can't be found
+        let effect = parse(&format!("wgsl = {:?}\n[[emitter]]\n", FORCE));
+        let wgsl = effect.wgsl.expect("no wgsl");
+        assert_eq!(wgsl.source, FORCE);
+        assert_eq!(wgsl.line, None);
+    }
+
+    #[test]
+    fn describe_line_counts_from_the_snippet() {
+        let wgsl = snippet(FORCE, Some(4));
+        assert_eq!(wgsl.describe_line(1), "effect line 4");
+        assert_eq!(wgsl.describe_line(3), "effect line 6");
+
+        let escaped = snippet(FORCE, None);
+        assert_eq!(
+            escaped.describe_line(2),
+            "effect wgsl line 2 (not a line of the file, it couldn't be found there)"
+        );
+    }
+
+    #[test]
+    fn describe_line_knows_the_defaults() {
+        let wgsl = snippet("fn helper() -> f32 {\n    return 1.0;\n}", Some(10));
+        let source = wgsl.with_defaults();
+
+        // the built-in `force` comes straight after the snippet's last line
+        let force = |source: &str| source.lines().position(|line| line.starts_with("fn force"));
+        let in_behaviour =
+            force(shaders::BEHAVIOUR_SHADER).expect("no force in behaviour.wgsl") + 1;
+        assert_eq!(force(&source), Some(3 + in_behaviour - 1));
+        assert_eq!(
+            wgsl.describe_line(3 + in_behaviour),
+            format!(
+                "built-in behaviour.wgsl line {}, which the effect didn't replace",
+                in_behaviour
+            )
+        );
+    }
+
+    #[test]
+    fn defaults_only_fill_in_what_is_missing() {
+        let own = snippet(FORCE, None);
+        assert_eq!(own.with_defaults(), FORCE);
+
+        let helper = snippet("fn helper() -> f32 { return 1.0; }", None);
+        let source = helper.with_defaults();
+        assert!(source.starts_with("fn helper() -> f32 { return 1.0; }\n"));
+        assert!(source.ends_with(shaders::BEHAVIOUR_SHADER));
+
+        let empty = snippet("", None);
+        assert_eq!(empty.with_defaults(), shaders::BEHAVIOUR_SHADER);
+    }
+
+    #[test]
+    fn validate_wants_emitters_with_a_lifetime() {
+        let invalid = |source| matches!(Effect::parse(source), Err(EffectError::Invalid(_)));
+        assert!(invalid(""));
+        assert!(invalid("[[emitter]]\nlifetime = nan\n"));
+        assert!(invalid("[[emitter]]\nlifetime = 0.0\n"));
+        parse("[[emitter]]\nlifetime = 1.0\n");
+    }
+}
@@ -1,21 +1,5 @@
-[[block]]
-struct HelperData {
-    maxParticles: u32;
-};
-
-[[block]]
-struct Uniforms {
-    paused: u32;
-    mouse_down: u32;
-    mouse_pos_last: vec3<f32>;
-};
-
-struct Particle {
-    pos : vec3<f32>;
-    vel : vec3<f32>;
-    col : vec4<f32>; // color+brightness
-    lifetime : f32;
-};
+#define DEAD_COUNT i32 // atomic in compute.wgsl
+#include "common.wgsl"
 
 struct VertexOut {
     [[location(0)]] col: vec3<f32>;
@@ -30,27 +14,25 @@ struct Particles {
 };
 
 [[group(0), binding(0)]] var<storage, read> particlesSrc : Particles;
+[[group(0), binding(1)]] var<storage, read> aliveList : AliveList;
 // should this be in bind_group 1?
 [[group(1), binding(0)]] var<storage, read> helperData : HelperData;
-[[group(1), binding(1)]] var<uniform> uniforms : Uniforms;
 
 [[group(2), binding(0)]] var r_color: texture_2d<u32>;
 [[group(2), binding(1)]] var r_sampler: sampler;
 
+// drawn indirectly, one instance (of 3 vertices) per live particle
 [[stage(vertex)]]
-fn main([[builtin(vertex_index)]] idx: u32) -> VertexOut {
-    let obj_idx = u32(floor(f32(idx) / 3.0));
-    let rel_idx = idx % 3u;
-
-    let p = particlesSrc.particles[obj_idx];
+fn main(
+    [[builtin(vertex_index)]] rel_idx: u32,
+    [[builtin(instance_index)]] instance: u32,
+) -> VertexOut {
+    let index = aliveList.indices[instance];
+    let p = particlesSrc.particles[index];
 
     var vertex = vec3<f32>(0.0, 0.0, p.pos.z);
     var tex_coord = vec2<f32>(0.0);
 
-    if (p.lifetime <= 0.0) {
-        return VertexOut(p.col.xyz, tex_coord, p.lifetime, vec4<f32>(0.0, 0.0, -2.0, -2.0));
-    }
-
     // for the eventual 3D transforms... I'm going to need more brain power
 
     if (rel_idx == 0u ) {
@@ -67,7 +49,7 @@ fn main([[builtin(vertex_index)]] idx: u32) -> VertexOut {
         tex_coord = vec2<f32>(1.0, 0.78);
     }
 
-    return VertexOut(p.col.xyz, tex_coord, p.lifetime / 600.0, vec4<f32>(vertex, 1.0));
+    return VertexOut(p.col.xyz, tex_coord, p.lifetime / p.max_lifetime, vec4<f32>(vertex, 1.0));
 }
 
 
@@ -75,7 +57,7 @@ fn main([[builtin(vertex_index)]] idx: u32) -> VertexOut {
 fn main(idkbro: VertexOut) -> [[location(0)]] vec4<f32> {
     //return textureSample(r_color, r_sampler, idkbro.tex_coords);
 
-    let tex = textureLoad(r_color, vec2<i32>(idkbro.tex_coords * 512.0), 0);
+    let tex = textureLoad(r_color, vec2<i32>(idkbro.tex_coords * vec2<f32>(textureDimensions(r_color))), 0);
     let brightness = f32(tex.x) / 255.0;
     let base_brightness = brightness * idkbro.lifetime * idkbro.lifetime;
     var color = vec4<f32>(1.0 - idkbro.lifetime, base_brightness, idkbro.lifetime, base_brightness);
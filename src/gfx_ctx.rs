@@ -1,76 +1,325 @@
 // use futures::executor::block_on;
-use wgpu::{PresentMode, RequestAdapterOptions, SurfaceConfiguration, TextureUsages, TextureView};
+use wgpu::{
+    Adapter, Backends, DownlevelFlags, Instance, PresentMode, RequestAdapterOptions,
+    SurfaceConfiguration, SurfaceError, TextureFormat, TextureUsages, TextureView,
+};
+use winit::dpi::PhysicalSize;
 use winit::window::Window;
 
+use std::fmt;
+
+/// The particle buffer lives in a single storage binding, which needs to hold
+/// at least one dispatch's worth of particles (16k * 64 bytes).
+const MIN_STORAGE_BUFFER_BINDING_SIZE: u32 = 1 << 20;
+
+/// Both simulations draw the particles straight out of a storage buffer. Compute shaders are optional,
+/// without them the particles are simulated on the CPU (see `Simulation::supported_by`).
+const REQUIRED_DOWNLEVEL_FLAGS: DownlevelFlags = DownlevelFlags::VERTEX_STORAGE;
+
+/// Everything that can go wrong while setting up the GPU, with enough detail to tell the user what to do about it.
+#[derive(Debug)]
+pub enum GfxError {
+    /// No adapter on any backend, not even the software fallback.
+    NoAdapter { preferred: Backends },
+    /// The adapter exists but can't give us a limit we depend on.
+    LimitTooLow {
+        adapter: String,
+        limit: &'static str,
+        required: u32,
+        supported: u32,
+    },
+    /// The adapter is missing some of `REQUIRED_DOWNLEVEL_FLAGS`, GL ES 3.0 and WebGL2 ones for instance.
+    Unsupported {
+        adapter: String,
+        missing: DownlevelFlags,
+    },
+    /// The surface and adapter have no texture format in common.
+    UnsupportedFormat { adapter: String },
+    /// The adapter refused to hand out a device.
+    DeviceRequest {
+        adapter: String,
+        source: wgpu::RequestDeviceError,
+    },
+}
+
+impl fmt::Display for GfxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GfxError::NoAdapter { preferred } => write!(
+                f,
+                "no graphics adapter found (tried {:?}, every other backend, and the software fallback). \
+                 Check that your GPU drivers are installed, or try another backend with e.g. WGPU_BACKEND=gl. \
+                 In a browser, make sure WebGPU is enabled.",
+                preferred
+            ),
+            GfxError::LimitTooLow {
+                adapter,
+                limit,
+                required,
+                supported,
+            } => write!(
+                f,
+                "'{}' only supports {} = {}, but at least {} is needed. \
+                 Try a different GPU or backend (WGPU_BACKEND=vulkan, dx12, metal...).",
+                adapter, limit, supported, required
+            ),
+            GfxError::Unsupported { adapter, missing } => write!(
+                f,
+                "'{}' can't run the demo, it doesn't support {:?}. \
+                 Try a different GPU or backend (WGPU_BACKEND=vulkan, dx12, metal...).",
+                adapter, missing
+            ),
+            GfxError::UnsupportedFormat { adapter } => write!(
+                f,
+                "'{}' can't present to this window: the surface has no compatible texture format. \
+                 Try a different backend with WGPU_BACKEND.",
+                adapter
+            ),
+            GfxError::DeviceRequest { adapter, source } => write!(
+                f,
+                "'{}' refused to create a device ({}). \
+                 Updating your GPU drivers or picking another backend with WGPU_BACKEND may help.",
+                adapter, source
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GfxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GfxError::DeviceRequest { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Where frames end up: a window's swapchain, or a texture we own (for CI boxes, render servers).
+pub enum RenderTarget {
+    Window {
+        window: winit::window::Window,
+        surface: wgpu::Surface,
+    },
+    Offscreen {
+        texture: wgpu::Texture,
+    },
+}
+
+/// A frame acquired from the render target. Call `present` once it's been rendered to.
+pub enum Frame<'a> {
+    Surface(wgpu::SurfaceTexture),
+    Offscreen(&'a wgpu::Texture),
+}
+
+impl Frame<'_> {
+    pub fn texture(&self) -> &wgpu::Texture {
+        match self {
+            Frame::Surface(frame) => &frame.texture,
+            Frame::Offscreen(texture) => texture,
+        }
+    }
+
+    pub fn present(self) {
+        if let Frame::Surface(frame) = self {
+            frame.present();
+        }
+    }
+}
+
 pub struct GraphicsContext {
-    pub window: winit::window::Window,
-    pub surface: wgpu::Surface,
+    pub target: RenderTarget,
     pub device: wgpu::Device,
     pub queue: wgpu::Queue,
     pub config: wgpu::SurfaceConfiguration,
     pub size: winit::dpi::PhysicalSize<u32>,
     pub msaa_framebuffer: TextureView,
     pub sample_count: u32,
+    pub adapter_info: wgpu::AdapterInfo,
+    /// Which of the things older hardware can lack the adapter has, compute shaders especially.
+    /// See `Simulation::supported_by`.
+    pub downlevel: wgpu::DownlevelCapabilities,
 }
 
 impl GraphicsContext {
-    pub(crate) async fn new(window: Window, sample_count: u32) -> Self {
+    /// `backends` is tried first; if it yields no usable adapter we fall back through the rest.
+    pub(crate) async fn new(
+        window: Window,
+        sample_count: u32,
+        present_mode: PresentMode,
+        backends: Backends,
+    ) -> Result<Self, GfxError> {
         let size = window.inner_size();
 
-        let instance = wgpu::Instance::new(wgpu::Backends::VULKAN);
+        // every backend is enabled on the instance so the fallbacks have something to pick from
+        let instance = wgpu::Instance::new(Backends::all());
 
         let surface = unsafe { instance.create_surface(&window) };
 
-        // TODO: hey asshole, fix this later - we need to know if a given adapter will be supported.
-        let adapter = instance
-            .request_adapter(&RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::HighPerformance,
-                force_fallback_adapter: false,
-                compatible_surface: Some(&surface),
-            })
+        let adapter = select_adapter(&instance, backends, Some(&surface))
             .await
-            .unwrap();
+            .ok_or(GfxError::NoAdapter {
+                preferred: backends,
+            })?;
 
-        println!("{:?}", adapter.get_info().backend);
+        let info = adapter.get_info();
+        println!("{} ({:?})", info.name, info.backend);
 
-        let (device, queue) = adapter
-            .request_device(
-                &wgpu::DeviceDescriptor {
-                    features: wgpu::Features::empty(),
-                    limits: wgpu::Limits {
-                        max_storage_buffer_binding_size: 256 << 20,
-                        ..wgpu::Limits::default()
-                    }, // so we can run on webgl
-                    label: None,
-                },
-                None, // Trace path
-            )
-            .await
-            .unwrap();
+        let (device, queue) = Self::request_device(&adapter).await?;
+
+        let format =
+            surface
+                .get_preferred_format(&adapter)
+                .ok_or_else(|| GfxError::UnsupportedFormat {
+                    adapter: info.name.clone(),
+                })?;
 
         let config = SurfaceConfiguration {
             usage: TextureUsages::RENDER_ATTACHMENT,
-            format: surface.get_preferred_format(&adapter).unwrap(),
+            format,
             width: size.width,
             height: size.height,
-            // just for performance testing
-            present_mode: PresentMode::Mailbox,
+            present_mode,
         };
 
         surface.configure(&device, &config);
 
         let msaa_framebuffer = Self::create_msaa_framebuffer(&config, &device, sample_count);
 
-        Self {
-            window,
-            surface,
+        Ok(Self {
+            target: RenderTarget::Window { window, surface },
+            device,
+            queue,
+            config,
+            size,
+            msaa_framebuffer,
+            sample_count,
+            adapter_info: info,
+            downlevel: adapter.get_downlevel_properties(),
+        })
+    }
+
+    /// Creates a context with no window or surface, rendering into an owned texture instead.
+    /// Prefers a real adapter, but will settle for a software/fallback one.
+    pub(crate) async fn new_headless(
+        size: PhysicalSize<u32>,
+        sample_count: u32,
+        backends: Backends,
+    ) -> Result<Self, GfxError> {
+        let instance = wgpu::Instance::new(Backends::all());
+
+        let adapter =
+            select_adapter(&instance, backends, None)
+                .await
+                .ok_or(GfxError::NoAdapter {
+                    preferred: backends,
+                })?;
+
+        let info = adapter.get_info();
+        println!("{} ({:?}, headless)", info.name, info.backend);
+
+        let (device, queue) = Self::request_device(&adapter).await?;
+
+        let config = SurfaceConfiguration {
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+            format: TextureFormat::Rgba8UnormSrgb,
+            width: size.width.max(1),
+            height: size.height.max(1),
+            // meaningless without a surface, but keeps the config complete
+            present_mode: PresentMode::Immediate,
+        };
+
+        let texture = Self::create_offscreen_texture(&config, &device);
+        let msaa_framebuffer = Self::create_msaa_framebuffer(&config, &device, sample_count);
+
+        Ok(Self {
+            target: RenderTarget::Offscreen { texture },
             device,
             queue,
             config,
             size,
             msaa_framebuffer,
             sample_count,
+            adapter_info: info,
+            downlevel: adapter.get_downlevel_properties(),
+        })
+    }
+
+    async fn request_device(adapter: &Adapter) -> Result<(wgpu::Device, wgpu::Queue), GfxError> {
+        let adapter_name = adapter.get_info().name;
+
+        let missing = REQUIRED_DOWNLEVEL_FLAGS - adapter.get_downlevel_properties().flags;
+        if !missing.is_empty() {
+            return Err(GfxError::Unsupported {
+                adapter: adapter_name,
+                missing,
+            });
         }
+
+        let supported = adapter.limits().max_storage_buffer_binding_size;
+
+        if supported < MIN_STORAGE_BUFFER_BINDING_SIZE {
+            return Err(GfxError::LimitTooLow {
+                adapter: adapter_name,
+                limit: "max_storage_buffer_binding_size",
+                required: MIN_STORAGE_BUFFER_BINDING_SIZE,
+                supported,
+            });
+        }
+
+        adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    // timestamps are nice to have for the stats, see gpu_timer.rs
+                    features: adapter.features() & wgpu::Features::TIMESTAMP_QUERY,
+                    // take as much storage as the adapter will give us,
+                    // the particle capacity is picked to fit whatever that turns out to be
+                    limits: wgpu::Limits {
+                        max_storage_buffer_binding_size: supported,
+                        ..base_limits(adapter)
+                    },
+                    label: None,
+                },
+                None, // Trace path
+            )
+            .await
+            .map_err(|source| GfxError::DeviceRequest {
+                adapter: adapter_name,
+                source,
+            })
+    }
+
+    /// The window we're presenting to, `None` when headless.
+    pub fn window(&self) -> Option<&Window> {
+        match &self.target {
+            RenderTarget::Window { window, .. } => Some(window),
+            RenderTarget::Offscreen { .. } => None,
+        }
+    }
+
+    /// Acquires the next frame to render into. Returns `None` if the surface timed out.
+    pub fn current_frame(&self) -> Option<Frame<'_>> {
+        let surface = match &self.target {
+            RenderTarget::Window { surface, .. } => surface,
+            RenderTarget::Offscreen { texture } => return Some(Frame::Offscreen(texture)),
+        };
+
+        let frame = surface.get_current_texture();
+
+        let frame = match frame {
+            Ok(_f) => _f,
+            Err(SurfaceError::Outdated) => {
+                surface.configure(&self.device, &self.config);
+                surface
+                    .get_current_texture()
+                    .expect("swapchain failed to get current frame (twice)")
+            }
+            Err(SurfaceError::Timeout) => {
+                return None; /*assume gpu is asleep?*/
+            }
+            _ => frame.expect("swapchain failed to get current frame"),
+        };
+
+        Some(Frame::Surface(frame))
     }
 
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
@@ -78,13 +327,40 @@ impl GraphicsContext {
             self.size = new_size;
             self.config.width = new_size.width;
             self.config.height = new_size.height;
-            self.surface.configure(&self.device, &self.config);
+
+            match &mut self.target {
+                RenderTarget::Window { surface, .. } => {
+                    surface.configure(&self.device, &self.config)
+                }
+                RenderTarget::Offscreen { texture } => {
+                    *texture = Self::create_offscreen_texture(&self.config, &self.device)
+                }
+            }
         }
 
         self.msaa_framebuffer =
             Self::create_msaa_framebuffer(&self.config, &self.device, self.sample_count);
     }
 
+    fn create_offscreen_texture(
+        config: &wgpu::SurfaceConfiguration,
+        device: &wgpu::Device,
+    ) -> wgpu::Texture {
+        device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width: config.width,
+                height: config.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: config.format,
+            usage: config.usage,
+            label: Some("offscreen render target"),
+        })
+    }
+
     fn create_msaa_framebuffer(
         config: &wgpu::SurfaceConfiguration,
         device: &wgpu::Device,
@@ -111,3 +387,113 @@ impl GraphicsContext {
             .create_view(&wgpu::TextureViewDescriptor::default())
     }
 }
+
+/// Backends to fall through, in order, when the preferred set has nothing to offer.
+#[cfg(not(target_arch = "wasm32"))]
+const FALLBACK_BACKENDS: [Backends; 5] = [
+    Backends::VULKAN,
+    Backends::METAL,
+    Backends::DX12,
+    Backends::GL,
+    Backends::DX11,
+];
+
+/// Finds an adapter on `preferred` if possible, then on each remaining backend,
+/// and finally settles for the fallback (software) adapter.
+async fn select_adapter(
+    instance: &Instance,
+    preferred: Backends,
+    surface: Option<&wgpu::Surface>,
+) -> Option<Adapter> {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let mut attempts = vec![preferred];
+        attempts.extend(
+            FALLBACK_BACKENDS
+                .iter()
+                .copied()
+                .filter(|backend| !preferred.contains(*backend)),
+        );
+
+        for backends in attempts {
+            let best = instance
+                .enumerate_adapters(backends)
+                .filter(|adapter| surface.map_or(true, |s| adapter.is_surface_supported(s)))
+                // one we'd only turn down in `request_device` isn't worth stopping the search for
+                .filter(meets_requirements)
+                .min_by_key(|adapter| device_type_rank(adapter.get_info().device_type));
+
+            if best.is_some() {
+                return best;
+            }
+
+            println!(
+                "no usable adapter on {:?}, trying the next backend",
+                backends
+            );
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    {
+        let _ = preferred; // the browser picks the backend for us
+
+        let adapter = instance
+            .request_adapter(&RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                force_fallback_adapter: false,
+                compatible_surface: surface,
+            })
+            .await;
+
+        if adapter.is_some() {
+            return adapter;
+        }
+    }
+
+    instance
+        .request_adapter(&RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            force_fallback_adapter: true,
+            compatible_surface: surface,
+        })
+        .await
+}
+
+/// What to ask for, storage aside. wgpu's defaults assume compute shaders, so an adapter without them
+/// (simulating on the CPU) gets what it has instead.
+fn base_limits(adapter: &Adapter) -> wgpu::Limits {
+    let compute = adapter
+        .get_downlevel_properties()
+        .flags
+        .contains(DownlevelFlags::COMPUTE_SHADERS);
+    if compute {
+        wgpu::Limits::default()
+    } else {
+        adapter.limits()
+    }
+}
+
+/// Whether `request_device` will take this adapter.
+#[cfg(not(target_arch = "wasm32"))]
+fn meets_requirements(adapter: &Adapter) -> bool {
+    adapter
+        .get_downlevel_properties()
+        .flags
+        .contains(REQUIRED_DOWNLEVEL_FLAGS)
+        && adapter.limits().max_storage_buffer_binding_size >= MIN_STORAGE_BUFFER_BINDING_SIZE
+}
+
+/// Lower is better - real GPUs first, software rasterizers last.
+#[cfg(not(target_arch = "wasm32"))]
+fn device_type_rank(device_type: wgpu::DeviceType) -> u8 {
+    use wgpu::DeviceType;
+
+    match device_type {
+        DeviceType::DiscreteGpu => 0,
+        DeviceType::IntegratedGpu => 1,
+        DeviceType::VirtualGpu => 2,
+        DeviceType::Other => 3,
+        DeviceType::Cpu => 4,
+    }
+}
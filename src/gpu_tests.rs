@@ -0,0 +1,640 @@
+//! Runs the compute shaders for real, on whatever adapter there is (a software one on CI), and checks what
+//! they did to a handful of particles, some by hand and some against `CpuSim`. With no adapter at all (or one
+//! that can't do compute) they fail, unless `PARTICLES_SKIP_GPU_TESTS` is set, in which case they say so and
+//! return without checking anything.
+
+use crate::cpu_sim::CpuSim;
+use crate::effect::{self, BlendMode, Effect, Emitter, Shape};
+use crate::pipelines::{
+    ColorTarget, DrawArgs, Particle, ParticleSystem, ParticleSystemConfig, Simulation, Uniforms,
+    UpdateParams, PARTICLE_GROUP_SIZE,
+};
+use crate::shaders::ShaderError;
+use crate::stages::WgslStage;
+use bytemuck::{Pod, Zeroable};
+use futures::executor::block_on;
+use wgpu::{
+    Backends, Buffer, BufferDescriptor, BufferUsages, Color, LoadOp, MapMode,
+    RequestAdapterOptions, TextureFormat,
+};
+
+use std::sync::Arc;
+
+/// One step at 60Hz, which is exactly one frame in the shaders' units.
+const DT: f32 = 1.0 / 60.0;
+
+/// Floats that went through the GPU, or `pow`, only match to about this much.
+const EPSILON: f32 = 1e-5;
+
+/// The same, after several steps of the GPU and CPU drifting apart.
+const DRIFT: f32 = 1e-4;
+
+/// A single group, so one workgroup of `step_particles` covers the lot.
+const CAPACITY: u32 = PARTICLE_GROUP_SIZE;
+
+/// What the particles are drawn into, when they're drawn at all.
+const TARGET_FORMAT: TextureFormat = TextureFormat::Rgba8Unorm;
+
+/// Set (to anything) to let the tests through on a machine that can't run them.
+const SKIP_VAR: &str = "PARTICLES_SKIP_GPU_TESTS";
+
+/// `Harness::new(effect)`, returning from the test if it had to be skipped.
+macro_rules! harness {
+    ($effect:expr) => {
+        match Harness::new($effect) {
+            Some(harness) => harness,
+            None => return,
+        }
+    };
+}
+
+struct Harness {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    system: ParticleSystem,
+}
+
+impl Harness {
+    /// Panics when there's no adapter that can run the compute shaders, or says why and returns `None`
+    /// if `SKIP_VAR` is set. Use it through `harness!`.
+    fn new(effect: &Effect) -> Option<Self> {
+        // a real adapter if there is one, otherwise the software fallback
+        let instance = wgpu::Instance::new(Backends::all());
+        let request = |force_fallback_adapter| {
+            block_on(instance.request_adapter(&RequestAdapterOptions {
+                force_fallback_adapter,
+                ..RequestAdapterOptions::default()
+            }))
+        };
+        let adapter = match request(false).or_else(|| request(true)) {
+            Some(adapter) => adapter,
+            None => return unavailable("no adapter"),
+        };
+
+        if Simulation::supported_by(&adapter.get_downlevel_properties()) != Some(Simulation::Gpu) {
+            return unavailable(&format!(
+                "{} can't run compute shaders",
+                adapter.get_info().name
+            ));
+        }
+
+        let (device, queue) = block_on(adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                label: None,
+                features: wgpu::Features::empty(),
+                limits: adapter.limits(),
+            },
+            None,
+        ))
+        .expect("couldn't get a device");
+
+        let system = ParticleSystem::new(
+            &device,
+            &queue,
+            TARGET_FORMAT,
+            &ParticleSystemConfig {
+                capacity: Some(CAPACITY),
+                effect: effect.clone(),
+                ..ParticleSystemConfig::default()
+            },
+        )
+        .unwrap_or_else(|e| panic!("{}", e));
+
+        Some(Harness {
+            device,
+            queue,
+            system,
+        })
+    }
+
+    /// Runs `step_particles` and then `emit` once, with the mouse at `mouse_pos` if it's `Some`.
+    fn step(&mut self, mouse_pos: Option<[f32; 2]>) {
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        self.system
+            .update(&self.queue, &mut encoder, &params(mouse_pos));
+        self.queue.submit(Some(encoder.finish()));
+    }
+
+    fn upload_particles(&self, particles: &[Particle]) {
+        self.system
+            .upload_particles(&self.device, &self.queue, particles);
+    }
+
+    fn particles(&self) -> Vec<Particle> {
+        self.read(&self.system.compute.particle_buffer, CAPACITY as usize)
+    }
+
+    /// How many particles the compute passes counted as alive, i.e. how many would be drawn.
+    fn alive(&self) -> u32 {
+        self.read::<DrawArgs>(self.system.draw_args(), 1)[0].instance_count
+    }
+
+    /// Copies the first `count` `T`s in `buffer` back, waiting for the GPU to get there.
+    fn read<T: Pod>(&self, buffer: &Buffer, count: usize) -> Vec<T> {
+        let size = (count * std::mem::size_of::<T>()) as u64;
+        let staging = self.staging_buffer(size);
+
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        encoder.copy_buffer_to_buffer(buffer, 0, &staging, 0, size);
+        self.queue.submit(Some(encoder.finish()));
+
+        self.map(&staging, count)
+    }
+
+    /// Copies back the pixels of a square RGBA8 `texture`, `size` wide, row by row.
+    fn read_texture(&self, texture: &wgpu::Texture, size: u32) -> Vec<[u8; 4]> {
+        // rows have to be a multiple of 256 bytes apart, which 64 pixels and up are
+        let bytes_per_row = size * 4;
+        let staging = self.staging_buffer(bytes_per_row as u64 * size as u64);
+
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &staging,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(bytes_per_row),
+                    rows_per_image: None,
+                },
+            },
+            wgpu::Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: 1,
+            },
+        );
+        self.queue.submit(Some(encoder.finish()));
+
+        self.map(&staging, (size * size) as usize)
+    }
+
+    fn staging_buffer(&self, size: u64) -> Buffer {
+        self.device.create_buffer(&BufferDescriptor {
+            label: Some("test readback"),
+            size,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// The `count` `T`s in `staging`, waiting for the GPU to finish writing them.
+    fn map<T: Pod>(&self, staging: &Buffer, count: usize) -> Vec<T> {
+        let mapped = staging.slice(..).map_async(MapMode::Read);
+        self.device.poll(wgpu::Maintain::Wait);
+        block_on(mapped).expect("couldn't map the readback buffer");
+
+        // copied out rather than cast, the mapping is only guaranteed to be aligned for `T` if it's small
+        let mut read = vec![T::zeroed(); count];
+        bytemuck::cast_slice_mut(&mut read).copy_from_slice(&staging.slice(..).get_mapped_range());
+        staging.unmap();
+        read
+    }
+}
+
+/// Skips the test if `SKIP_VAR` says to, fails it otherwise.
+fn unavailable<T>(why: &str) -> Option<T> {
+    if std::env::var_os(SKIP_VAR).is_none() {
+        panic!("{}, set {} to skip the GPU tests", why, SKIP_VAR);
+    }
+    eprintln!("skipping, {}", why);
+    None
+}
+
+fn params(mouse_pos: Option<[f32; 2]>) -> UpdateParams {
+    UpdateParams {
+        emit_pos: mouse_pos.unwrap_or_default(),
+        emitting: mouse_pos.is_some(),
+        dt: DT,
+        time: 0.0,
+        time_scale: 1.0,
+        paused: false,
+    }
+}
+
+fn effect(emitter: Emitter) -> Effect {
+    Effect {
+        emitters: vec![emitter],
+        ..Effect::default()
+    }
+}
+
+fn particle(pos: [f32; 3], vel: [f32; 3], lifetime: f32) -> Particle {
+    Particle {
+        pos,
+        vel,
+        col: [1.0; 4],
+        lifetime,
+        max_lifetime: lifetime,
+        ..Particle::zeroed()
+    }
+}
+
+fn assert_close(actual: &[f32], expected: &[f32]) {
+    assert_within(actual, expected, EPSILON);
+}
+
+fn assert_within(actual: &[f32], expected: &[f32], tolerance: f32) {
+    let close = actual
+        .iter()
+        .zip(expected)
+        .all(|(a, e)| (a - e).abs() <= tolerance);
+    assert!(
+        close && actual.len() == expected.len(),
+        "expected {:?}, got {:?}",
+        expected,
+        actual
+    );
+}
+
+#[test]
+fn step_moves_and_ages_particles() {
+    let mut harness = harness!(&effect(Emitter {
+        friction: 0.5,
+        ..Emitter::default()
+    }));
+
+    harness.upload_particles(&[
+        particle([0.1, 0.2, 0.5], [0.01, -0.02, 0.0], 10.0),
+        // loses 0.16 a frame, so this one dies
+        particle([0.0, 0.0, 0.5], [0.01, 0.01, 0.0], 0.1),
+    ]);
+    harness.step(None);
+
+    let particles = harness.particles();
+    let moved = &particles[0];
+    assert_close(&moved.pos, &[0.11, 0.18, 0.5]);
+    assert_close(&moved.vel, &[0.005, -0.01, 0.0]);
+    assert_close(&[moved.lifetime, moved.max_lifetime], &[9.84, 10.0]);
+
+    assert!(particles[1].lifetime <= 0.0, "{:?}", particles[1]);
+    assert!(particles[2..].iter().all(|p| p.lifetime == 0.0));
+
+    assert_eq!(harness.alive(), 1);
+}
+
+#[test]
+fn emit_spawns_at_the_mouse() {
+    let count = 8;
+    let speed = 0.01;
+    let mut harness = harness!(&effect(Emitter {
+        count,
+        shape: Shape::Ring,
+        speed,
+        lifetime: 100.0,
+        offset: [0.1, 0.0],
+        ..Emitter::default()
+    }));
+
+    harness.step(Some([0.25, -0.5]));
+
+    let emitted: Vec<_> = harness
+        .particles()
+        .into_iter()
+        .filter(|p| p.lifetime > 0.0)
+        .collect();
+    assert_eq!(emitted.len(), count as usize);
+    assert_eq!(harness.alive(), count);
+
+    let mut directions = Vec::new();
+    for p in &emitted {
+        assert_close(&p.pos, &[0.35, -0.5, 0.5]);
+        assert_close(&[p.lifetime, p.max_lifetime], &[100.0, 100.0]);
+        assert_eq!(p.emitter, 0);
+
+        assert_close(&[p.vel[0].hypot(p.vel[1]), p.vel[2]], &[speed, 0.0]);
+        let turns = p.vel[1].atan2(p.vel[0]) / std::f32::consts::TAU;
+        directions.push((turns * count as f32).round().rem_euclid(count as f32) as u32);
+    }
+
+    // a ring spreads them evenly, one per direction
+    directions.sort_unstable();
+    assert_eq!(directions, (0..count).collect::<Vec<_>>());
+}
+
+#[test]
+fn emit_stops_when_the_pool_is_full() {
+    let mut harness = harness!(&effect(Emitter {
+        count: 8,
+        ..Emitter::default()
+    }));
+
+    // room for 6 more
+    let full = vec![particle([0.0, 0.0, 0.5], [0.0; 3], 10.0); CAPACITY as usize - 6];
+    harness.upload_particles(&full);
+    harness.step(Some([0.0, 0.0]));
+
+    let particles = harness.particles();
+    assert!(particles.iter().all(|p| p.lifetime > 0.0));
+    assert_eq!(
+        particles
+            .iter()
+            .filter(|p| p.max_lifetime == Emitter::default().lifetime)
+            .count(),
+        6
+    );
+    assert_eq!(harness.alive(), CAPACITY);
+}
+
+/// The live particles in an order that doesn't depend on which slots they ended up in,
+/// which is down to the order the GPU's atomics happened to go in.
+fn sorted_alive(particles: &[Particle]) -> Vec<Particle> {
+    let mut alive: Vec<_> = particles
+        .iter()
+        .copied()
+        .filter(|p| p.lifetime > 0.0)
+        .collect();
+    alive.sort_by(|a, b| {
+        // bursts differ in lifetime, and no two particles in a burst head off at the same sine
+        let key = |p: &Particle| (p.emitter, p.max_lifetime, p.lifetime, p.vel[1], p.vel[0]);
+        key(a)
+            .partial_cmp(&key(b))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    alive
+}
+
+#[test]
+fn matches_the_cpu_reference() {
+    let effect = Effect {
+        emitters: vec![
+            Emitter {
+                count: 5,
+                shape: Shape::Disc,
+                speed: 0.01,
+                lifetime: 50.0,
+                friction: 0.9,
+                offset: [0.0, 0.1],
+            },
+            Emitter {
+                count: 7,
+                shape: Shape::Ring,
+                speed: 0.02,
+                lifetime: 2.0,
+                friction: 0.99,
+                offset: [-0.1, 0.0],
+            },
+        ],
+        ..Effect::default()
+    };
+    let mut harness = harness!(&effect);
+    let mut sim = CpuSim::new(CAPACITY);
+
+    let initial: Vec<_> = (0..20)
+        .map(|i| {
+            let i = i as f32;
+            Particle {
+                emitter: i as u32 % 2,
+                ..particle(
+                    [i * 0.01, -i * 0.02, 0.5],
+                    [0.001 * i, 0.002 - 0.0003 * i, 0.0],
+                    0.5 + i,
+                )
+            }
+        })
+        .collect();
+    harness.upload_particles(&initial);
+    sim.upload(&initial);
+
+    // a few bursts, with steps in between for the older particles to slow down and die
+    let effect_params = effect.to_params();
+    for step in 0..12 {
+        let mouse_pos = match step % 4 {
+            0 => Some([0.2 * step as f32, -0.3]),
+            _ => None,
+        };
+
+        harness.step(mouse_pos);
+        sim.step(&Uniforms::from_params(&params(mouse_pos)), &effect_params);
+
+        let gpu = sorted_alive(&harness.particles());
+        let cpu = sorted_alive(sim.particles());
+        assert_eq!(gpu.len(), cpu.len(), "live particles after step {}", step);
+        assert_eq!(harness.alive() as usize, sim.alive().len());
+
+        for (gpu, cpu) in gpu.iter().zip(&cpu) {
+            assert_eq!(gpu.emitter, cpu.emitter);
+            assert_within(&gpu.pos, &cpu.pos, DRIFT);
+            assert_within(&gpu.vel, &cpu.vel, DRIFT);
+            assert_within(
+                &[gpu.lifetime, gpu.max_lifetime],
+                &[cpu.lifetime, cpu.max_lifetime],
+                DRIFT,
+            );
+        }
+    }
+}
+
+#[test]
+fn render_draws_over_the_scene() {
+    const SIZE: u32 = 512;
+    const MIDDLE: i32 = SIZE as i32 / 2;
+
+    let mut harness = harness!(&effect(Emitter::default()));
+    harness.upload_particles(&[particle([0.0, 0.0, 0.5], [0.0; 3], 10.0)]);
+    harness.step(None);
+
+    let texture = harness.device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("test scene"),
+        size: wgpu::Extent3d {
+            width: SIZE,
+            height: SIZE,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: TARGET_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+    });
+    let view = texture.create_view(&Default::default());
+
+    // the "scene" is just a clear, the particles should go on top without wiping it
+    let mut encoder = harness.device.create_command_encoder(&Default::default());
+    encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some("test scene"),
+        color_attachments: &[wgpu::RenderPassColorAttachment {
+            view: &view,
+            resolve_target: None,
+            ops: wgpu::Operations {
+                load: LoadOp::Clear(Color {
+                    r: 0.0,
+                    g: 0.0,
+                    b: 0.5,
+                    a: 1.0,
+                }),
+                store: true,
+            },
+        }],
+        depth_stencil_attachment: None,
+    });
+    harness.system.render(
+        &mut encoder,
+        &ColorTarget {
+            view: &view,
+            msaa_view: None,
+            load: LoadOp::Load,
+        },
+    );
+    harness.queue.submit(Some(encoder.finish()));
+
+    let pixels = harness.read_texture(&texture, SIZE);
+    let scene = pixels[0];
+    assert!(scene[2] > 0, "the scene got cleared away: {:?}", scene);
+
+    // the one particle is a triangle a few pixels across, in the middle
+    let drawn: Vec<_> = (0..SIZE * SIZE)
+        .filter(|&i| pixels[i as usize] != scene)
+        .map(|i| ((i % SIZE) as i32, (i / SIZE) as i32))
+        .collect();
+    assert!(!drawn.is_empty(), "no particle drawn");
+    assert!(
+        drawn
+            .iter()
+            .all(|&(x, y)| (x - MIDDLE).abs() <= 8 && (y - MIDDLE).abs() <= 8),
+        "drawn outside the particle: {:?}",
+        drawn
+    );
+}
+
+#[test]
+fn systems_sharing_pipelines_keep_their_own_particles() {
+    let mut harness = harness!(&effect(Emitter {
+        count: 3,
+        ..Emitter::default()
+    }));
+    let mut other = ParticleSystem::with_pipelines(
+        &harness.device,
+        &harness.queue,
+        harness.system.pipelines().clone(),
+        &ParticleSystemConfig {
+            capacity: Some(CAPACITY * 2),
+            effect: Effect {
+                blend: BlendMode::Alpha,
+                ..effect(Emitter {
+                    count: 5,
+                    ..Emitter::default()
+                })
+            },
+            ..ParticleSystemConfig::default()
+        },
+    )
+    .unwrap_or_else(|e| panic!("{}", e));
+    assert_eq!(other.capacity(), CAPACITY * 2);
+
+    harness.step(Some([0.0, 0.0]));
+    let mut encoder = harness.device.create_command_encoder(&Default::default());
+    other.update(&harness.queue, &mut encoder, &params(Some([0.0, 0.0])));
+    harness.queue.submit(Some(encoder.finish()));
+
+    assert_eq!(harness.alive(), 3);
+    assert_eq!(
+        harness.read::<DrawArgs>(other.draw_args(), 1)[0].instance_count,
+        5
+    );
+}
+
+#[test]
+fn added_stages_run_after_the_built_in_ones() {
+    const PAINT_RED: &str = r#"
+#include "compute_common.wgsl"
+
+[[stage(compute), workgroup_size(STEP_WORKGROUP_SIZE, 1, 1)]]
+fn paint_red([[builtin(global_invocation_id)]] global_invocation_id: vec3<u32>) {
+    if (global_invocation_id.x >= helperData.maxParticles / PARTICLE_GROUP_SIZE) {
+        return;
+    }
+
+    for(var y: u32 = 0u; y < PARTICLE_GROUP_SIZE; y = y + 1u) {
+        particlesSrc.group[global_invocation_id.x][y].col = vec4<f32>(1.0, 0.0, 0.0, 1.0);
+    }
+}
+"#;
+
+    let mut harness = harness!(&effect(Emitter::default()));
+    let stage = WgslStage::new(
+        &harness.device,
+        harness.system.pipelines(),
+        "paint_red.wgsl",
+        PAINT_RED,
+        "paint_red",
+        None,
+        WgslStage::per_particle_group,
+    )
+    .unwrap_or_else(|e| panic!("{}", e));
+    harness.system.add_stage(stage);
+    assert_eq!(harness.system.stages().len(), 3);
+
+    harness.upload_particles(&[particle([0.0; 3], [0.01, 0.0, 0.0], 10.0)]);
+    harness.step(None);
+
+    let particles = harness.particles();
+    assert_eq!(particles[0].col, [1.0, 0.0, 0.0, 1.0]);
+    // the physics still ran first
+    assert!((particles[0].pos[0] - 0.01).abs() < EPSILON);
+}
+
+#[test]
+fn broken_stages_are_reported() {
+    let harness = harness!(&effect(Emitter::default()));
+    let result = WgslStage::new(
+        &harness.device,
+        harness.system.pipelines(),
+        "broken.wgsl",
+        "#include \"compute_common.wgsl\"\n\nfn oops( {}\n",
+        "oops",
+        None,
+        WgslStage::per_particle_group,
+    );
+
+    match result {
+        Err(ShaderError::Parse { at, .. }) => assert_eq!(at, "broken.wgsl:3"),
+        Err(e) => panic!("wrong error: {}", e),
+        Ok(_) => panic!("broken stage compiled"),
+    }
+}
+
+#[test]
+fn effect_wgsl_adds_a_force() {
+    let effect = Effect::parse(
+        r#"
+wgsl = '''
+fn force(p: Particle, t: f32) -> vec3<f32> {
+    return vec3<f32>(0.0, -0.001, 0.0);
+}
+'''
+
+# friction 1.0 so the force is all that changes the velocity
+[[emitter]]
+friction = 1.0
+"#,
+    )
+    .unwrap_or_else(|e| panic!("{}", e));
+
+    let mut harness = harness!(&effect);
+    harness.upload_particles(&[particle([0.0; 3], [0.0; 3], 10.0)]);
+    harness.step(None);
+
+    // one frame's acceleration, then moved by the new velocity
+    let particle = harness.particles()[0];
+    assert_close(&particle.vel, &[0.0, -0.001, 0.0]);
+    assert_close(&particle.pos, &[0.0, -0.001, 0.0]);
+}
+
+#[test]
+fn broken_effect_wgsl_keeps_the_previous_pipelines() {
+    let mut harness = harness!(&effect(Emitter::default()));
+    let before = harness.system.pipelines().clone();
+
+    let broken = Effect::parse(effect::BROKEN_EFFECT).unwrap_or_else(|e| panic!("{}", e));
+    match harness
+        .system
+        .set_effect(&harness.device, &harness.queue, &broken)
+    {
+        Err(ShaderError::Parse { at, .. }) => assert_eq!(at, "effect line 5"),
+        Err(e) => panic!("wrong error: {}", e),
+        Ok(()) => panic!("broken wgsl compiled"),
+    }
+
+    assert!(Arc::ptr_eq(harness.system.pipelines(), &before));
+}
@@ -0,0 +1,155 @@
+use wgpu::{
+    Buffer, BufferDescriptor, BufferUsages, CommandEncoder, Features, QuerySet, QuerySetDescriptor,
+    QueryType,
+};
+
+/// Passes we can time in a single frame. Anything past this (lots of fixed steps) goes untimed.
+const MAX_TIMED_PASSES: usize = 32;
+
+/// Size of a resolved timestamp.
+pub const TIMESTAMP_SIZE: u64 = 8;
+
+/// Room for the timestamps of a whole frame, a begin and an end per pass.
+pub const MAX_TIMESTAMPS_SIZE: u64 = MAX_TIMED_PASSES as u64 * 2 * TIMESTAMP_SIZE;
+
+/// The passes in a frame, as far as timing goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pass {
+    Physics,
+    Emission,
+    Render,
+}
+
+struct Queries {
+    set: QuerySet,
+    resolved: Buffer,
+}
+
+/// Brackets passes with timestamp queries, on devices with `TIMESTAMP_QUERY`. Everywhere else
+/// it does nothing, so callers don't need to care.
+pub struct GpuTimer {
+    queries: Option<Queries>,
+    /// Passes timed so far this frame, each one's timestamps are at `2 * i` and `2 * i + 1`.
+    passes: Vec<Pass>,
+    open: bool,
+}
+
+impl GpuTimer {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let queries = if device.features().contains(Features::TIMESTAMP_QUERY) {
+            Some(Queries {
+                set: device.create_query_set(&QuerySetDescriptor {
+                    label: Some("pass timestamps"),
+                    ty: QueryType::Timestamp,
+                    count: MAX_TIMED_PASSES as u32 * 2,
+                }),
+                resolved: device.create_buffer(&BufferDescriptor {
+                    label: Some("resolved pass timestamps"),
+                    size: MAX_TIMESTAMPS_SIZE,
+                    usage: BufferUsages::COPY_SRC | BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                }),
+            })
+        } else {
+            None
+        };
+
+        GpuTimer {
+            queries,
+            passes: Vec::with_capacity(MAX_TIMED_PASSES),
+            open: false,
+        }
+    }
+
+    /// One that never times anything, for when nobody's looking at the timings.
+    pub fn disabled() -> Self {
+        GpuTimer {
+            queries: None,
+            passes: Vec::new(),
+            open: false,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.queries.is_some()
+    }
+
+    /// Call right before beginning `pass` on `encoder`.
+    pub fn begin(&mut self, encoder: &mut CommandEncoder, pass: Pass) {
+        let queries = match &self.queries {
+            Some(queries) if self.passes.len() < MAX_TIMED_PASSES => queries,
+            _ => return,
+        };
+
+        encoder.write_timestamp(&queries.set, self.passes.len() as u32 * 2);
+        self.passes.push(pass);
+        self.open = true;
+    }
+
+    /// Call right after the pass started by `begin` has ended.
+    pub fn end(&mut self, encoder: &mut CommandEncoder) {
+        if let (Some(queries), true) = (&self.queries, self.open) {
+            encoder.write_timestamp(&queries.set, self.passes.len() as u32 * 2 - 1);
+            self.open = false;
+        }
+    }
+
+    /// Resolves this frame's timestamps and starts the next frame. Returns the buffer they were
+    /// resolved into, and which pass each pair belongs to.
+    pub fn resolve(&mut self, encoder: &mut CommandEncoder) -> Option<(&Buffer, Vec<Pass>)> {
+        let queries = self.queries.as_ref()?;
+        if self.passes.is_empty() {
+            return None;
+        }
+
+        let passes = std::mem::take(&mut self.passes);
+        encoder.resolve_query_set(
+            &queries.set,
+            0..passes.len() as u32 * 2,
+            &queries.resolved,
+            0,
+        );
+
+        Some((&queries.resolved, passes))
+    }
+}
+
+/// GPU time spent in each kind of pass over a frame, in milliseconds.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PassTimings {
+    pub physics: f32,
+    pub emission: f32,
+    pub render: f32,
+}
+
+impl PassTimings {
+    /// `period` is nanoseconds per tick, from `Queue::get_timestamp_period`.
+    pub fn new(passes: &[Pass], timestamps: &[u64], period: f32) -> Self {
+        let mut timings = PassTimings::default();
+
+        for (pass, pair) in passes.iter().zip(timestamps.chunks_exact(2)) {
+            let ms = (pair[1].saturating_sub(pair[0]) as f64 * period as f64 / 1_000_000.0) as f32;
+
+            match pass {
+                Pass::Physics => timings.physics += ms,
+                Pass::Emission => timings.emission += ms,
+                Pass::Render => timings.render += ms,
+            }
+        }
+
+        timings
+    }
+
+    #[cfg(feature = "tracy")]
+    pub fn plot(&self) {
+        use profiling::tracy_client::{create_plot, Plot};
+
+        static PHYSICS: Plot = create_plot!("gpu physics pass (ms)");
+        static EMISSION: Plot = create_plot!("gpu emission pass (ms)");
+        static RENDER: Plot = create_plot!("gpu render pass (ms)");
+
+        PHYSICS.point(self.physics as f64);
+        EMISSION.point(self.emission as f64);
+        RENDER.point(self.render as f64);
+    }
+}
@@ -0,0 +1,94 @@
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use particles_demo::shaders::{SHADER_DIR, SHADER_FILES};
+
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
+
+/// What changed on disk since the last `poll`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Changes {
+    pub shaders: bool,
+    /// Indices into the paths given to `HotReload::new`.
+    pub effects: Vec<usize>,
+}
+
+/// Watches the WGSL in the source tree (and the effect files, if there are any) for edits.
+pub struct HotReload {
+    // dropping the watcher stops the events
+    _watcher: RecommendedWatcher,
+    events: Receiver<DebouncedEvent>,
+    shaders: Vec<PathBuf>,
+    effects: Vec<PathBuf>,
+}
+
+impl HotReload {
+    pub fn new(effects: &[PathBuf]) -> notify::Result<Self> {
+        let (tx, events) = channel();
+        let mut watcher = notify::watcher(tx, Duration::from_millis(100))?;
+
+        // watch directories rather than files, editors love to save by replacing the file
+        let shader_dir = Path::new(SHADER_DIR);
+        watcher.watch(shader_dir, RecursiveMode::NonRecursive)?;
+
+        let effects = effects
+            .iter()
+            .map(|path| {
+                let path = path.canonicalize()?;
+                if let Some(dir) = path.parent() {
+                    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+                }
+                Ok(path)
+            })
+            .collect::<notify::Result<_>>()?;
+
+        println!("watching {} for shader changes", shader_dir.display());
+
+        Ok(HotReload {
+            _watcher: watcher,
+            events,
+            shaders: SHADER_FILES
+                .iter()
+                .map(|file| shader_dir.join(file))
+                .collect(),
+            effects,
+        })
+    }
+
+    /// Drains pending file events without blocking.
+    pub fn poll(&self) -> Changes {
+        let mut changes = Changes::default();
+
+        for event in self.events.try_iter() {
+            let path = match event {
+                DebouncedEvent::Write(path)
+                | DebouncedEvent::Create(path)
+                | DebouncedEvent::Rename(_, path) => path,
+                DebouncedEvent::Error(e, _) => {
+                    eprintln!("file watcher: {}", e);
+                    continue;
+                }
+                _ => continue,
+            };
+
+            if self.shaders.iter().any(|shader| same_file(shader, &path)) {
+                changes.shaders = true;
+            }
+            for (i, effect) in self.effects.iter().enumerate() {
+                if same_file(effect, &path) && !changes.effects.contains(&i) {
+                    changes.effects.push(i);
+                }
+            }
+        }
+
+        changes
+    }
+
+    pub fn effect_path(&self, index: usize) -> &Path {
+        &self.effects[index]
+    }
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    a == b || matches!((a.canonicalize(), b.canonicalize()), (Ok(a), Ok(b)) if a == b)
+}
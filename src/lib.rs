@@ -0,0 +1,70 @@
+//! GPU particles for wgpu, as used by the particles demo.
+//!
+//! A [`ParticleSystem`] owns its buffers and pipelines but not the device, surface or command encoders,
+//! so it can be dropped into any renderer: record `update` into the frame's encoder, then `render` into any
+//! texture of the format and sample count it was created with (or `draw` into a render pass you already have).
+//!
+//! ```no_run
+//! use particles_demo::{ColorTarget, ParticleSystem, ParticleSystemConfig, Simulation, UpdateParams};
+//!
+//! fn frame(
+//!     device: &wgpu::Device,
+//!     queue: &wgpu::Queue,
+//!     view: &wgpu::TextureView,
+//!     particles: &mut ParticleSystem,
+//! ) {
+//!     let mut encoder = device.create_command_encoder(&Default::default());
+//!
+//!     let params = UpdateParams {
+//!         emit_pos: [0.0, 0.0],
+//!         emitting: true,
+//!         dt: 1.0 / 60.0,
+//!         time: 0.0,
+//!         time_scale: 1.0,
+//!         paused: false,
+//!     };
+//!     particles.update(queue, &mut encoder, &params);
+//!
+//!     // over whatever the host already drew this frame
+//!     let target = ColorTarget {
+//!         view,
+//!         msaa_view: None,
+//!         load: wgpu::LoadOp::Load,
+//!     };
+//!     particles.render(&mut encoder, &target);
+//!
+//!     queue.submit(Some(encoder.finish()));
+//! }
+//!
+//! # fn setup(adapter: &wgpu::Adapter, device: &wgpu::Device, queue: &wgpu::Queue) {
+//! let simulation = Simulation::supported_by(&adapter.get_downlevel_properties())
+//!     .expect("the adapter can't draw from storage buffers");
+//! let config = ParticleSystemConfig {
+//!     simulation,
+//!     ..ParticleSystemConfig::default()
+//! };
+//! let format = wgpu::TextureFormat::Bgra8UnormSrgb;
+//! // only an effect's WGSL hooks can fail to compile, and the default effect has none
+//! let particles = ParticleSystem::new(device, queue, format, &config).unwrap();
+//! # }
+//! ```
+
+pub mod cpu_sim;
+pub mod effect;
+#[cfg(test)]
+mod gpu_tests;
+pub mod gpu_timer;
+pub mod pipelines;
+pub mod preprocess;
+pub mod shaders;
+pub mod stages;
+pub mod texture;
+
+pub use crate::effect::{BlendMode, Effect};
+pub use crate::pipelines::{
+    particle_capacity, ColorTarget, ParticleSystem, ParticleSystemConfig, Pipelines, Simulation,
+    UpdateParams,
+};
+pub use crate::shaders::{ShaderError, Sources};
+pub use crate::stages::{ParticleStage, StageStep, WgslStage};
+pub use crate::texture::ParticleTexture;
@@ -1,31 +1,54 @@
 // mod framework;
+#[cfg(not(target_arch = "wasm32"))]
+mod bench;
 mod gfx_ctx;
-mod pipelines;
-
-use crate::gfx_ctx::GraphicsContext;
-use crate::pipelines::{RenderStuff, Uniforms, MAX_PARTICLES};
+#[cfg(not(target_arch = "wasm32"))]
+mod hot_reload;
+mod options;
+mod stats;
+mod timing;
 
-use wgpu::{
-    Color, ComputePassDescriptor, LoadOp, RenderBundle, RenderPassColorAttachment,
-    RenderPassDescriptor, SurfaceError,
+#[cfg(not(target_arch = "wasm32"))]
+use crate::bench::Bench;
+use crate::gfx_ctx::{GfxError, GraphicsContext};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::hot_reload::HotReload;
+use crate::options::Options;
+use crate::stats::StatsCollector;
+use crate::timing::{Clock, Tick};
+use particles_demo::gpu_timer::Pass;
+#[cfg(not(target_arch = "wasm32"))]
+use particles_demo::shaders;
+use particles_demo::{
+    particle_capacity, ColorTarget, Effect, ParticleSystem, ParticleSystemConfig, ParticleTexture,
+    Pipelines, Simulation, UpdateParams,
 };
 
-use winit::event::Event;
+use wgpu::{Color, LoadOp, RenderBundle, RenderPassColorAttachment};
+
 use winit::event::VirtualKeyCode::P;
+use winit::event::{Event, VirtualKeyCode};
 
 use std::panic;
+use std::path::Path;
+use std::sync::Arc;
 
 #[cfg(target_arch = "wasm32")]
 use web_sys::window;
 
 use winit::event_loop::ControlFlow;
 use winit::event_loop::*;
-use winit::window::Fullscreen;
 use winit::window::Fullscreen::Borderless;
 use winit_input_helper::WinitInputHelper;
 // extern crate console_error_panic_hook;
 // use std::panic;
 
+/// Steps run by shift+. while paused.
+const STEP_BURST: u32 = 10;
+
+/// What the particles are drawn over.
+const BACKGROUND: Color = Color::BLACK;
+
 pub enum ShouldQuit {
     True,
     False,
@@ -33,11 +56,71 @@ pub enum ShouldQuit {
 
 struct State {
     gc: GraphicsContext,
-    render_stuff: RenderStuff,
+    /// One per effect, updated and drawn in order. They all share the same pipelines.
+    systems: Vec<ParticleSystem>,
     input_helper: WinitInputHelper,
+    #[cfg(not(target_arch = "wasm32"))]
+    hot_reload: Option<HotReload>,
+    /// Last known cursor position, in clip space.
+    mouse_pos: [f32; 2],
+    /// While paused the compute passes are skipped, but we keep rendering the frozen particles.
+    paused: bool,
+    /// Steps to run this frame even though we're paused.
+    pending_steps: u32,
+    clock: Clock,
+    /// How much to simulate this frame, from `clock`.
+    tick: Tick,
+    /// What each of this frame's steps gets, worked out in `update`.
+    params: UpdateParams,
+    stats: StatsCollector,
+    /// Window title without the stats on the end.
+    title: String,
+    /// Scripted input and timing collection for `--bench`.
+    #[cfg(not(target_arch = "wasm32"))]
+    bench: Option<Bench>,
 }
 
 impl State {
+    fn new(
+        gc: GraphicsContext,
+        systems: Vec<ParticleSystem>,
+        title: String,
+        fixed_dt: Option<f64>,
+    ) -> Self {
+        let stats = StatsCollector::new(&gc.device, &gc.queue, systems.len());
+
+        State {
+            gc,
+            systems,
+            input_helper: WinitInputHelper::new(),
+            #[cfg(not(target_arch = "wasm32"))]
+            hot_reload: None,
+            mouse_pos: [0.0, 0.0],
+            paused: false,
+            pending_steps: 0,
+            clock: Clock::new(fixed_dt),
+            // without a clock driving it (headless), one 60Hz step per frame
+            tick: Tick {
+                dt: 1.0 / 60.0,
+                time_scale: 1.0,
+                time: 0.0,
+                steps: 1,
+            },
+            params: UpdateParams {
+                emit_pos: [0.0, 0.0],
+                emitting: false,
+                dt: 1.0 / 60.0,
+                time: 0.0,
+                time_scale: 1.0,
+                paused: false,
+            },
+            stats,
+            title,
+            #[cfg(not(target_arch = "wasm32"))]
+            bench: None,
+        }
+    }
+
     #[cfg_attr(feature = "tracy", profiling::function)]
     pub fn handle_events(&mut self, event: &Event<()>) -> ShouldQuit {
         let has_events = self.input_helper.update(event);
@@ -52,6 +135,12 @@ impl State {
 
             #[cfg(feature = "tracy")]
             profiling::finish_frame!();
+
+            #[cfg(not(target_arch = "wasm32"))]
+            if self.bench.as_ref().map_or(false, Bench::finished) {
+                self.finish_bench();
+                return ShouldQuit::True;
+            }
         }
 
         let input_helper = &mut self.input_helper;
@@ -69,127 +158,499 @@ impl State {
 
     #[cfg_attr(feature = "tracy", profiling::function)]
     fn update(&mut self) {
+        #[cfg(not(target_arch = "wasm32"))]
+        self.reload_changed_files();
+
+        // [ and ] halve and double every particle pool
+        if self.input_helper.key_pressed(VirtualKeyCode::RBracket) {
+            self.resize_particles(|capacity| capacity.saturating_mul(2));
+        }
+        if self.input_helper.key_pressed(VirtualKeyCode::LBracket) {
+            self.resize_particles(|capacity| capacity / 2);
+        }
+
+        // P pauses and resumes, . pauses and steps once (shift+. steps STEP_BURST times)
+        if self.input_helper.key_pressed(P) {
+            self.paused = !self.paused;
+            println!("{}", if self.paused { "paused" } else { "resumed" });
+        }
+        if self.input_helper.key_pressed(VirtualKeyCode::Period) {
+            if !self.paused {
+                self.paused = true;
+                println!("paused");
+            }
+            self.pending_steps += if self.input_helper.held_shift() {
+                STEP_BURST
+            } else {
+                1
+            };
+        }
+
+        // - and = halve and double the speed, 0 goes back to real time
+        let time_scale = self.clock.time_scale();
+        if self.input_helper.key_pressed(VirtualKeyCode::Minus) {
+            self.set_time_scale(time_scale / 2.0);
+        }
+        if self.input_helper.key_pressed(VirtualKeyCode::Equals) {
+            self.set_time_scale(time_scale * 2.0);
+        }
+        if self.input_helper.key_pressed(VirtualKeyCode::Key0) {
+            self.set_time_scale(1.0);
+        }
+
         if let Some(mouse) = self.input_helper.mouse() {
             let mouse = (
                 mouse.0 / self.gc.size.width as f32,
                 mouse.1 / self.gc.size.height as f32,
             );
 
-            let mut uniforms = Uniforms {
-                paused: 0,
-                mouse_down: 0,
-                mouse_pos_last: [(mouse.0 * 2.0) - 1.0, mouse.1 * (-2.0) + 1.0],
-            };
+            self.mouse_pos = [(mouse.0 * 2.0) - 1.0, mouse.1 * (-2.0) + 1.0];
+        }
 
-            if self.input_helper.mouse_pressed(0) || self.input_helper.mouse_held(0) {
-                uniforms.mouse_down = 1;
-            }
+        self.tick = if !self.paused {
+            self.clock.tick()
+        } else if self.pending_steps > 0 {
+            self.clock.step(self.pending_steps)
+        } else {
+            self.clock.tick_paused()
+        };
+
+        let mut mouse_down = self.input_helper.mouse_pressed(0) || self.input_helper.mouse_held(0);
+
+        // the benchmark ignores the real mouse and clock
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(bench) = &self.bench {
+            let input = bench.input();
+            self.mouse_pos = input.mouse_pos;
+            mouse_down = input.mouse_down;
+            self.tick = bench.tick();
+        }
+
+        self.params = UpdateParams {
+            emit_pos: self.mouse_pos,
+            emitting: mouse_down,
+            dt: self.tick.dt,
+            time: self.tick.time,
+            time_scale: self.tick.time_scale,
+            paused: self.paused && self.pending_steps == 0,
+        };
+        self.pending_steps = 0;
 
-            self.gc.queue.write_buffer(
-                &self.render_stuff.shared.uniforms,
-                0,
-                bytemuck::cast_slice(&[uniforms]),
+        if self.stats.update(&self.gc.device, self.capacity()) {
+            self.show_stats();
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(bench) = &mut self.bench {
+            bench.frame_done(&self.stats);
+        }
+    }
+
+    /// Waits for the GPU to catch up, then writes out the benchmark report.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn finish_bench(&mut self) {
+        let bench = match self.bench.take() {
+            Some(bench) => bench,
+            None => return,
+        };
+
+        self.gc.device.poll(wgpu::Maintain::Wait);
+
+        let report = bench.report(&self.gc, self.capacity());
+        if let Err(e) = bench.write_report(&report) {
+            eprintln!("couldn't write the benchmark report: {}", e);
+        }
+    }
+
+    /// Puts the live particle count, frame rate and GPU pass times in the title bar. On the web they're
+    /// picked up through `particleStats()` instead.
+    fn show_stats(&self) {
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(window) = self.gc.window() {
+            let stats = &self.stats.stats;
+            let mut title = format!(
+                "{} - {} / {} particles - {:.0} fps",
+                self.title, stats.alive, stats.capacity, stats.fps
             );
+            if stats.gpu_timing {
+                title += &format!(
+                    " - gpu: physics {:.2} ms, emission {:.2} ms, render {:.2} ms",
+                    stats.physics_ms, stats.emission_ms, stats.render_ms
+                );
+            }
+            window.set_title(&title);
         }
     }
 
-    #[cfg_attr(feature = "tracy", profiling::function)]
-    fn render(&self) {
-        let frame_tex = {
-            let frame = self.gc.surface.get_current_texture();
-
-            match frame {
-                Ok(_f) => _f,
-                Err(SurfaceError::Outdated) => {
-                    self.gc.surface.configure(&self.gc.device, &self.gc.config);
-                    self.gc
-                        .surface
-                        .get_current_texture()
-                        .expect("swapchain failed to get current frame (twice)")
-                }
-                Err(SurfaceError::Timeout) => {
-                    return; /*assume gpu is asleep?*/
+    /// Picks up edited shaders and effect files. Anything that doesn't compile or parse
+    /// is reported and ignored, so the last working version keeps running.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn reload_changed_files(&mut self) {
+        let hot_reload = match &self.hot_reload {
+            Some(hot_reload) => hot_reload,
+            None => return,
+        };
+
+        let changes = hot_reload.poll();
+        let (gc, systems) = (&self.gc, &mut self.systems);
+
+        if changes.shaders {
+            // compiled once per set of pipelines (effects with hooks have their own),
+            // and only swapped in if they all worked
+            let reloaded = shaders::Sources::read_from_disk()
+                .map_err(|e| e.to_string())
+                .and_then(|sources| {
+                    let mut reloaded: Vec<(Arc<Pipelines>, Arc<Pipelines>)> = Vec::new();
+                    for system in systems.iter() {
+                        let old = system.pipelines();
+                        if !reloaded.iter().any(|(done, _)| Arc::ptr_eq(done, old)) {
+                            let new = old
+                                .reload(&gc.device, &sources)
+                                .map_err(|e| e.to_string())?;
+                            reloaded.push((old.clone(), Arc::new(new)));
+                        }
+                    }
+                    Ok(reloaded)
+                });
+
+            match reloaded {
+                Ok(reloaded) => {
+                    for system in systems.iter_mut() {
+                        let (_, new) = reloaded
+                            .iter()
+                            .find(|(old, _)| Arc::ptr_eq(old, system.pipelines()))
+                            .expect("every system's pipelines were reloaded");
+                        system.set_pipelines(new.clone());
+                    }
+                    println!("reloaded shaders");
                 }
-                _ => frame.expect("swapchain failed to get current frame"),
+                Err(e) => eprintln!("{}\nkeeping the previous shaders", e),
+            }
+        }
+
+        for &index in &changes.effects {
+            let path = hot_reload.effect_path(index);
+            let system = &mut systems[index];
+            let reloaded = Effect::load(path)
+                .map_err(|e| e.to_string())
+                .and_then(|effect| {
+                    let texture = load_texture(&effect)?;
+                    system
+                        .set_effect(&gc.device, &gc.queue, &effect)
+                        .map_err(|e| e.to_string())?;
+                    system.set_texture(&gc.device, &gc.queue, &texture);
+
+                    // a capacity in the file wins over `[` and `]`, as it does over --particles at startup
+                    if let Some(requested) = effect.capacity {
+                        let capacity = particle_capacity(
+                            &gc.device.limits(),
+                            system.simulation(),
+                            Some(requested),
+                        );
+                        if capacity != system.capacity() {
+                            system.resize(&gc.device, &gc.queue, capacity);
+                            println!("particle capacity: {}", capacity);
+                        }
+                    }
+                    Ok(())
+                });
+
+            match reloaded {
+                Ok(()) => println!("reloaded {}", path.display()),
+                Err(e) => eprintln!("{}: {}\nkeeping the previous effect", path.display(), e),
             }
+        }
+    }
+
+    /// Resizes every system's pool to `resize` of its current capacity.
+    fn resize_particles(&mut self, resize: fn(u32) -> u32) {
+        let limits = self.gc.device.limits();
+
+        for system in &mut self.systems {
+            let capacity = particle_capacity(
+                &limits,
+                system.simulation(),
+                Some(resize(system.capacity())),
+            );
+
+            if capacity != system.capacity() {
+                system.resize(&self.gc.device, &self.gc.queue, capacity);
+            }
+        }
+        println!("particle capacity: {}", self.capacity());
+    }
+
+    /// Room for particles over all the systems.
+    fn capacity(&self) -> u32 {
+        self.systems.iter().map(ParticleSystem::capacity).sum()
+    }
+
+    fn set_time_scale(&mut self, time_scale: f32) {
+        let time_scale = self.clock.set_time_scale(time_scale);
+        println!("time scale: {}x", time_scale);
+    }
+
+    #[cfg_attr(feature = "tracy", profiling::function)]
+    fn render(&mut self) {
+        let frame_tex = match self.gc.current_frame() {
+            Some(frame) => frame,
+            None => return,
         };
 
         let mut encoder = self.gc.device.create_command_encoder(&Default::default());
 
-        {
-            let mut cpass = encoder.begin_compute_pass(&ComputePassDescriptor {
-                label: Some("physics compute pass"),
-            });
-            cpass.set_pipeline(&self.render_stuff.compute.compute_pipeline);
-            cpass.set_bind_group(0, &self.render_stuff.compute.bind_group, &[]);
-            cpass.set_bind_group(1, &self.render_stuff.shared.compute_bind_group, &[]);
-            cpass.dispatch(((MAX_PARTICLES + 63) as f32 / 256f32 / 64f32) as u32, 1, 1);
+        // with a fixed timestep there can be several steps in a frame (or none at all)
+        for _ in 0..self.tick.steps {
+            for system in &mut self.systems {
+                system.update_timed(
+                    &self.gc.queue,
+                    &mut encoder,
+                    &self.params,
+                    &mut self.stats.timer,
+                );
+            }
         }
 
+        self.stats.timer.begin(&mut encoder, Pass::Render);
         {
-            let mut emitpass = encoder.begin_compute_pass(&ComputePassDescriptor {
-                label: Some("emission pass"),
-            });
-            emitpass.set_pipeline(&self.render_stuff.compute.emit_pipeline);
-            emitpass.set_bind_group(0, &self.render_stuff.compute.bind_group, &[]);
-            emitpass.set_bind_group(1, &self.render_stuff.shared.compute_bind_group, &[]);
-            emitpass.dispatch(1, 1, 1);
+            let view = frame_tex
+                .texture()
+                .create_view(&wgpu::TextureViewDescriptor::default());
+
+            let target = ColorTarget {
+                view: &view,
+                msaa_view: match self.gc.sample_count {
+                    1 => None,
+                    _ => Some(&self.gc.msaa_framebuffer),
+                },
+                load: LoadOp::Clear(BACKGROUND),
+            };
+
+            // one pass for everything, each system drawn over the ones before it
+            let mut render_pass = target.begin_render_pass(&mut encoder);
+            for system in &self.systems {
+                system.draw(&mut render_pass);
+            }
         }
+        self.stats.timer.end(&mut encoder);
 
-        {
-            let view = &frame_tex
-                .texture
-                .create_view(&wgpu::TextureViewDescriptor::default());
+        let draw_args: Vec<_> = self.systems.iter().map(ParticleSystem::draw_args).collect();
+        self.stats.record(&mut encoder, &draw_args);
+
+        self.gc.queue.submit(Some(encoder.finish()));
+        self.stats.submitted();
+        frame_tex.present();
+    }
+}
+
+/// The effect files from the options, or the built-in effect if there aren't any.
+fn load_effects(options: &Options) -> Vec<(Effect, ParticleTexture)> {
+    let exit = |path: &Path, e: String| -> ! {
+        eprintln!("{}: {}", path.display(), e);
+        std::process::exit(1);
+    };
+
+    if options.effects.is_empty() {
+        return vec![(Effect::default(), ParticleTexture::built_in())];
+    }
+
+    options
+        .effects
+        .iter()
+        .map(|path| {
+            let effect = Effect::load(path).unwrap_or_else(|e| exit(path, e.to_string()));
+            let texture = load_texture(&effect).unwrap_or_else(|e| exit(path, e));
+            (effect, texture)
+        })
+        .collect()
+}
+
+/// The texture `effect` asks for, or the built-in one.
+fn load_texture(effect: &Effect) -> Result<ParticleTexture, String> {
+    match &effect.texture {
+        Some(path) => ParticleTexture::load(path)
+            .map_err(|e| format!("couldn't load texture {}: {}", path.display(), e)),
+        None => Ok(ParticleTexture::built_in()),
+    }
+}
 
-            let ops = wgpu::Operations {
-                load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
-                store: true,
+/// Builds a particle system per effect, each sized to fit the device we ended up with.
+fn create_particles(gc: &GraphicsContext, options: &Options) -> Vec<ParticleSystem> {
+    // `GraphicsContext` only takes adapters that can draw the particles, so one of them is supported
+    let supported =
+        Simulation::supported_by(&gc.downlevel).expect("the adapter can't draw the particles");
+    let simulation = if options.cpu_sim {
+        Simulation::Cpu
+    } else {
+        supported
+    };
+    match (simulation, supported) {
+        (Simulation::Cpu, Simulation::Gpu) => println!("simulating particles on the CPU"),
+        (Simulation::Cpu, Simulation::Cpu) => println!(
+            "{} can't run compute shaders, simulating particles on the CPU",
+            gc.adapter_info.name
+        ),
+        (Simulation::Gpu, _) => {}
+    }
+
+    let pipelines = Arc::new(Pipelines::new(
+        &gc.device,
+        gc.config.format,
+        gc.sample_count,
+        simulation,
+    ));
+
+    let systems: Vec<_> = load_effects(options)
+        .into_iter()
+        .enumerate()
+        .map(|(i, (effect, texture))| {
+            let name = match options.effects.get(i) {
+                Some(path) => path.display().to_string(),
+                None => "built-in effect".to_string(),
             };
+            if effect.wgsl.is_some() && simulation == Simulation::Cpu {
+                eprintln!("{}: wgsl hooks only run on the GPU, ignoring them", name);
+            }
 
-            let rpass_color_attachment = if self.gc.sample_count == 1 {
-                wgpu::RenderPassColorAttachment {
-                    view,
-                    resolve_target: None,
-                    ops,
-                }
-            } else {
-                wgpu::RenderPassColorAttachment {
-                    view: &self.gc.msaa_framebuffer,
-                    resolve_target: Some(view),
-                    ops,
-                }
+            let config = ParticleSystemConfig {
+                capacity: effect.capacity.or(options.particles),
+                effect,
+                texture,
+                simulation,
+                sample_count: gc.sample_count,
             };
+            ParticleSystem::with_pipelines(&gc.device, &gc.queue, pipelines.clone(), &config)
+                .unwrap_or_else(|e| {
+                    eprintln!("{}: {}", name, e);
+                    std::process::exit(1);
+                })
+        })
+        .collect();
+
+    let capacities: Vec<_> = systems
+        .iter()
+        .map(|system| system.capacity().to_string())
+        .collect();
+    println!("particle capacity: {}", capacities.join(" + "));
+
+    systems
+}
 
-            let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
-                label: Some("Render pass descriptor"),
-                color_attachments: &[rpass_color_attachment],
-                depth_stencil_attachment: None,
-            });
+#[cfg(not(target_arch = "wasm32"))]
+fn start_hot_reload(options: &Options) -> Option<HotReload> {
+    if !options.hot_reload {
+        return None;
+    }
 
-            render_pass.set_pipeline(&self.render_stuff.render.render_pipeline);
-            render_pass.set_bind_group(0, &self.render_stuff.render.bind_group, &[]);
-            render_pass.set_bind_group(1, &self.render_stuff.shared.render_bind_group, &[]);
-            render_pass.set_bind_group(2, &self.render_stuff.render.texture_bind_group, &[]);
-            render_pass.draw(0..((MAX_PARTICLES) * 3), 0..1);
+    match HotReload::new(&options.effects) {
+        Ok(hot_reload) => Some(hot_reload),
+        Err(e) => {
+            eprintln!(
+                "couldn't start watching for changes, hot reload is off: {}",
+                e
+            );
+            None
         }
+    }
+}
 
-        self.gc.queue.submit(Some(encoder.finish()));
-        frame_tex.present();
+/// Tells the user why we couldn't start, on the console natively or on the page in a browser.
+fn report_gfx_error(error: &GfxError) {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        eprintln!("couldn't start the particle demo: {}", error);
+        std::process::exit(1);
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    {
+        web_sys::console::error_1(&format!("couldn't start the particle demo: {}", error).into());
+
+        web_sys::window()
+            .and_then(|win| win.document())
+            .and_then(|doc| {
+                let body = doc.body()?;
+                let message = doc.create_element("p").ok()?;
+                message.set_text_content(Some(&format!(
+                    "Sorry, the particle demo couldn't start: {}",
+                    error
+                )));
+                body.append_child(&message).ok()
+            })
+            .expect("couldn't append error message to document body");
     }
 }
 
-async fn async_main() {
+/// Runs the simulation for a fixed number of frames into an offscreen texture, no window needed.
+#[cfg(not(target_arch = "wasm32"))]
+async fn headless_main(options: Options) {
+    const HEADLESS_FRAMES: u32 = 600;
+
+    let size = options
+        .size
+        .unwrap_or_else(|| winit::dpi::PhysicalSize::new(1280, 720));
+
+    let gc = match GraphicsContext::new_headless(size, options.sample_count, options.backends).await
+    {
+        Ok(gc) => gc,
+        Err(e) => return report_gfx_error(&e),
+    };
+    let systems = create_particles(&gc, &options);
+
+    let mut state = State::new(gc, systems, options.title, None);
+
+    if options.bench {
+        state.bench = Some(Bench::new(options.bench_frames, options.bench_out));
+
+        while state
+            .bench
+            .as_ref()
+            .map_or(false, |bench| !bench.finished())
+        {
+            state.update();
+            state.render();
+            // nothing to present means nothing holds the CPU back, so wait for the frame here or
+            // the frame times would only measure how fast it's submitted
+            state.gc.device.poll(wgpu::Maintain::Wait);
+        }
+        return state.finish_bench();
+    }
+
+    // hold the "mouse" down in the middle of the screen so there's something to simulate
+    state.params.emitting = true;
+
+    let capacity = state.capacity();
+    for _ in 0..HEADLESS_FRAMES {
+        state.render();
+        // frees the readback slots as the frames finish, as `State::update` does, so later frames get one
+        state.stats.update(&state.gc.device, capacity);
+    }
+
+    state.gc.device.poll(wgpu::Maintain::Wait);
+    state.stats.update(&state.gc.device, capacity);
+    println!(
+        "rendered {} headless frames, {} particles alive",
+        HEADLESS_FRAMES, state.stats.stats.alive
+    );
+}
+
+async fn async_main(options: Options) {
     #[cfg(feature = "tracy")]
     profiling::register_thread!("Main Thread");
 
     // env_logger::init();
     let event_loop = EventLoop::new();
 
-    let window = winit::window::WindowBuilder::new()
-        .with_title("particles!")
-        // .with_fullscreen(Some(Borderless(None)))
-        .build(&event_loop)
-        .unwrap();
+    let mut window_builder = winit::window::WindowBuilder::new().with_title(&options.title);
+
+    if let Some(size) = options.size {
+        window_builder = window_builder.with_inner_size(size);
+    }
+    if options.fullscreen {
+        window_builder = window_builder.with_fullscreen(Some(Borderless(None)));
+    }
+
+    let window = window_builder.build(&event_loop).unwrap();
 
     #[cfg(target_arch = "wasm32")]
     {
@@ -207,14 +668,29 @@ async fn async_main() {
             .expect("couldn't append canvas to document body");
     }
 
-    let mut gc = GraphicsContext::new(window, 1).await;
-    let render_stuff = RenderStuff::new(&mut gc);
-
-    let mut state = State {
-        gc,
-        render_stuff,
-        input_helper: WinitInputHelper::new(),
+    let gc = match GraphicsContext::new(
+        window,
+        options.sample_count,
+        options.present_mode,
+        options.backends,
+    )
+    .await
+    {
+        Ok(gc) => gc,
+        Err(e) => return report_gfx_error(&e),
     };
+    let systems = create_particles(&gc, &options);
+
+    let mut state = State::new(gc, systems, options.title.clone(), options.fixed_dt());
+
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        state.hot_reload = start_hot_reload(&options);
+
+        if options.bench {
+            state.bench = Some(Bench::new(options.bench_frames, options.bench_out.clone()));
+        }
+    }
 
     event_loop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::Poll;
@@ -227,11 +703,17 @@ async fn async_main() {
 
 #[cfg(not(target_arch = "wasm32"))]
 fn main() {
-    futures::executor::block_on(async_main());
+    let options = Options::from_args();
+
+    if options.headless {
+        futures::executor::block_on(headless_main(options));
+    } else {
+        futures::executor::block_on(async_main(options));
+    }
 }
 
 #[cfg(target_arch = "wasm32")]
 fn main() {
     use wasm_bindgen::{prelude::*, JsCast};
-    wasm_bindgen_futures::spawn_local(async_main());
+    wasm_bindgen_futures::spawn_local(async_main(Options::from_env()));
 }
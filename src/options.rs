@@ -0,0 +1,289 @@
+// parsing the command line is native only, wasm just gets the defaults
+#![cfg_attr(target_arch = "wasm32", allow(dead_code))]
+
+use wgpu::{Backends, PresentMode};
+use winit::dpi::PhysicalSize;
+
+use std::path::PathBuf;
+
+const USAGE: &str = "\
+particles-demo - pretty particles!
+
+USAGE:
+    particles-demo [OPTIONS]
+
+OPTIONS:
+    --preset <NAME>          low, medium or high - a starting point the other options override
+    --samples <N>            MSAA sample count, 1 or 4 [default: 1]
+    --present-mode <MODE>    fifo, mailbox or immediate [default: mailbox]
+    --particles <N>          particle capacity of each effect that doesn't set its own, clamped to
+                             what the adapter supports
+    --fixed-timestep <HZ>    simulate in fixed steps of 1/HZ seconds, for repeatable runs
+    --effect <FILE>          effect file describing the emitters, see effects/default.toml.
+                             Repeat to run several effects at once, drawn in the order given
+    --hot-reload             watch the shaders and effect files, reloading them when they change
+    --cpu-sim                simulate on the CPU, as on adapters that can't run compute shaders
+    --backend <LIST>         backends to try first, e.g. vulkan or gl,dx12 [default: primary]
+    --size <WxH>             window size, e.g. 1920x1080
+    --fullscreen             borderless fullscreen
+    --title <TITLE>          window title [default: particles!]
+    --headless               render offscreen for a while and exit, no window needed
+    --bench                  run a scripted workload with vsync off and print a JSON report
+    --bench-frames <N>       frames to measure in --bench, after a short warm-up [default: 1000]
+    --bench-out <FILE>       write the --bench report to FILE instead of stdout
+    -h, --help               print this and exit
+
+ENVIRONMENT:
+    WGPU_BACKEND             same as --backend
+    PARTICLES                same as --particles
+";
+
+/// Everything that used to be hard-coded in `async_main`.
+pub struct Options {
+    pub title: String,
+    pub sample_count: u32,
+    pub present_mode: PresentMode,
+    /// `None` means "as many as makes sense", see `pipelines::particle_capacity`.
+    pub particles: Option<u32>,
+    /// One particle system per effect, none means just the built-in effect.
+    pub effects: Vec<PathBuf>,
+    /// Simulation steps per second, `None` steps once per frame by however long the frame took.
+    pub fixed_timestep: Option<f64>,
+    pub backends: Backends,
+    pub size: Option<PhysicalSize<u32>>,
+    pub fullscreen: bool,
+    pub headless: bool,
+    pub hot_reload: bool,
+    /// Simulate on the CPU even if the adapter can do compute, see cpu_sim.rs.
+    pub cpu_sim: bool,
+    /// Run the benchmark instead of taking input, see bench.rs.
+    pub bench: bool,
+    pub bench_frames: u32,
+    /// Where the benchmark report goes, `None` is stdout.
+    pub bench_out: Option<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            title: "particles!".to_string(),
+            sample_count: 1,
+            // just for performance testing
+            present_mode: PresentMode::Mailbox,
+            particles: None,
+            effects: Vec::new(),
+            fixed_timestep: None,
+            backends: Backends::PRIMARY,
+            size: None,
+            fullscreen: false,
+            headless: false,
+            hot_reload: false,
+            cpu_sim: false,
+            bench: false,
+            bench_frames: 1000,
+            bench_out: None,
+        }
+    }
+}
+
+impl Options {
+    /// Defaults, adjusted by the environment (`WGPU_BACKEND`, `PARTICLES`).
+    pub fn from_env() -> Self {
+        let mut options = Options::default();
+
+        if let Some(backends) = wgpu::util::backend_bits_from_env() {
+            options.backends = backends;
+        }
+        if let Some(particles) = std::env::var("PARTICLES").ok().and_then(|p| p.parse().ok()) {
+            options.particles = Some(particles);
+        }
+
+        options
+    }
+
+    /// Parses the command line on top of `from_env`. Prints usage and exits on `--help` or bad input.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn from_args() -> Self {
+        match Self::parse(pico_args::Arguments::from_env()) {
+            Ok(options) => options,
+            Err(e) => {
+                eprintln!("error: {}\n\n{}", e, USAGE);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn parse(mut args: pico_args::Arguments) -> Result<Self, String> {
+        if args.contains(["-h", "--help"]) {
+            print!("{}", USAGE);
+            std::process::exit(0);
+        }
+
+        let mut options = Self::from_env();
+
+        let arg_err = |e: pico_args::Error| e.to_string();
+
+        if let Some(preset) = args
+            .opt_value_from_str::<_, String>("--preset")
+            .map_err(arg_err)?
+        {
+            options.apply_preset(&preset)?;
+        }
+
+        if let Some(samples) = args.opt_value_from_str("--samples").map_err(arg_err)? {
+            options.sample_count = parse_samples(samples)?;
+        }
+        if let Some(mode) = args
+            .opt_value_from_fn("--present-mode", parse_present_mode)
+            .map_err(arg_err)?
+        {
+            options.present_mode = mode;
+        }
+        if let Some(particles) = args.opt_value_from_str("--particles").map_err(arg_err)? {
+            options.particles = Some(particles);
+        }
+        options.effects = args
+            .values_from_os_str("--effect", parse_path)
+            .map_err(arg_err)?;
+        if let Some(hz) = args
+            .opt_value_from_str::<_, f64>("--fixed-timestep")
+            .map_err(arg_err)?
+        {
+            if !hz.is_finite() || hz <= 0.0 {
+                return Err(format!(
+                    "--fixed-timestep must be a positive number (got {})",
+                    hz
+                ));
+            }
+            options.fixed_timestep = Some(hz);
+        }
+        if let Some(backends) = args
+            .opt_value_from_fn("--backend", parse_backends)
+            .map_err(arg_err)?
+        {
+            options.backends = backends;
+        }
+        if let Some(size) = args
+            .opt_value_from_fn("--size", parse_size)
+            .map_err(arg_err)?
+        {
+            options.size = Some(size);
+        }
+        if let Some(title) = args.opt_value_from_str("--title").map_err(arg_err)? {
+            options.title = title;
+        }
+        if let Some(frames) = args.opt_value_from_str("--bench-frames").map_err(arg_err)? {
+            if frames == 0 {
+                return Err("--bench-frames must be at least 1".to_string());
+            }
+            options.bench_frames = frames;
+        }
+        if let Some(out) = args
+            .opt_value_from_os_str("--bench-out", parse_path)
+            .map_err(arg_err)?
+        {
+            options.bench_out = Some(out);
+        }
+
+        options.fullscreen |= args.contains("--fullscreen");
+        options.headless |= args.contains("--headless");
+        options.hot_reload |= args.contains("--hot-reload");
+        options.cpu_sim |= args.contains("--cpu-sim");
+        options.bench |= args.contains("--bench");
+
+        // measure how fast we can go, not the refresh rate
+        if options.bench {
+            options.present_mode = PresentMode::Immediate;
+        }
+
+        let rest = args.finish();
+        if !rest.is_empty() {
+            return Err(format!("unexpected arguments: {:?}", rest));
+        }
+
+        Ok(options)
+    }
+
+    /// Length of a fixed simulation step in seconds, if we're using them.
+    pub fn fixed_dt(&self) -> Option<f64> {
+        self.fixed_timestep.map(|hz| 1.0 / hz)
+    }
+
+    /// Quality presets, roughly "laptop on battery", "most machines" and "show-off".
+    fn apply_preset(&mut self, preset: &str) -> Result<(), String> {
+        let (sample_count, present_mode, particles) = match preset {
+            "low" => (1, PresentMode::Fifo, 131_072),
+            "medium" => (1, PresentMode::Fifo, 524_288),
+            "high" => (4, PresentMode::Mailbox, 2_097_152),
+            _ => {
+                return Err(format!(
+                    "unknown preset '{}', expected low, medium or high",
+                    preset
+                ))
+            }
+        };
+
+        self.sample_count = sample_count;
+        self.present_mode = present_mode;
+        self.particles = Some(particles);
+
+        Ok(())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn parse_path(path: &std::ffi::OsStr) -> Result<PathBuf, String> {
+    Ok(path.into())
+}
+
+fn parse_samples(samples: u32) -> Result<u32, String> {
+    match samples {
+        1 | 4 => Ok(samples),
+        _ => Err(format!(
+            "--samples must be 1 or 4 (got {}), that's all wgpu guarantees",
+            samples
+        )),
+    }
+}
+
+fn parse_present_mode(mode: &str) -> Result<PresentMode, String> {
+    match mode.to_lowercase().as_str() {
+        "fifo" | "vsync" => Ok(PresentMode::Fifo),
+        "mailbox" => Ok(PresentMode::Mailbox),
+        "immediate" => Ok(PresentMode::Immediate),
+        _ => Err(format!(
+            "unknown present mode '{}', expected fifo, mailbox or immediate",
+            mode
+        )),
+    }
+}
+
+fn parse_backends(list: &str) -> Result<Backends, String> {
+    let backends = wgpu::util::parse_backends_from_comma_list(&list.to_lowercase());
+
+    if backends.is_empty() {
+        Err(format!(
+            "no known backends in '{}', expected some of vulkan, metal, dx12, dx11, gl",
+            list
+        ))
+    } else {
+        Ok(backends)
+    }
+}
+
+fn parse_size(size: &str) -> Result<PhysicalSize<u32>, String> {
+    let bad_size = || format!("bad size '{}', expected WIDTHxHEIGHT like 1280x720", size);
+
+    let (width, height) = size
+        .split_once(|c| c == 'x' || c == 'X')
+        .ok_or_else(bad_size)?;
+    let width: u32 = width.trim().parse().map_err(|_| bad_size())?;
+    let height: u32 = height.trim().parse().map_err(|_| bad_size())?;
+
+    if width == 0 || height == 0 {
+        return Err(bad_size());
+    }
+
+    Ok(PhysicalSize::new(width, height))
+}
@@ -1,20 +1,139 @@
-use crate::gfx_ctx::GraphicsContext;
+use crate::cpu_sim::CpuSim;
+use crate::effect::{BlendMode, Effect, Snippet};
+use crate::gpu_timer::GpuTimer;
+use crate::shaders::{self, ShaderError, Sources};
+use crate::stages::{self, ParticleStage, StageStep};
+use crate::texture::ParticleTexture;
 use wgpu::util::{BufferInitDescriptor, DeviceExt};
 use wgpu::{
     BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout, BindGroupLayoutDescriptor,
     BindGroupLayoutEntry, BindingType, BlendComponent, BlendFactor, BlendOperation, BlendState,
-    Buffer, BufferBindingType, BufferUsages, ColorTargetState, ComputePipeline,
+    Buffer, BufferBindingType, BufferDescriptor, BufferUsages, ColorTargetState, ComputePipeline,
     ComputePipelineDescriptor, FragmentState, FrontFace, MultisampleState,
-    PipelineLayoutDescriptor, PolygonMode, PrimitiveState, PrimitiveTopology, RenderPipeline,
-    RenderPipelineDescriptor, SamplerBindingType, ShaderModule, ShaderModuleDescriptor,
-    ShaderSource, ShaderStages, TextureSampleType, TextureViewDimension, VertexState,
+    PipelineLayoutDescriptor, PolygonMode, PrimitiveState, PrimitiveTopology, RenderPass,
+    RenderPipeline, RenderPipelineDescriptor, SamplerBindingType, ShaderModule, ShaderStages,
+    TextureFormat, TextureSampleType, TextureViewDimension, VertexState,
 };
 
+use std::sync::Arc;
+
+/// How many particles we ask for when the user doesn't say otherwise.
+/// The adapter's limits may still cut this down, see `particle_capacity`.
 #[cfg(not(target_arch = "wasm32"))]
-pub const MAX_PARTICLES: u32 = 1_048_576;
+pub const DEFAULT_MAX_PARTICLES: u32 = 1_048_576;
 
 #[cfg(target_arch = "wasm32")]
-pub const MAX_PARTICLES: u32 = 393_216;
+pub const DEFAULT_MAX_PARTICLES: u32 = 393_216;
+
+/// The default when the particles are simulated on the CPU, which can't keep up with nearly as many.
+pub const DEFAULT_CPU_PARTICLES: u32 = 65_536;
+
+/// One particle as it sits in the storage buffer. Normally only the shaders write these (the tests
+/// upload their own with `ParticleSystem::upload_particles`), but the layout has to match `Particle`
+/// in the WGSL, see the tests at the bottom.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Particle {
+    pub(crate) pos: [f32; 3],
+    // vec3s are 16 byte aligned in WGSL
+    pub(crate) _pad0: f32,
+    pub(crate) vel: [f32; 3],
+    pub(crate) _pad1: f32,
+    /// Color + brightness.
+    pub(crate) col: [f32; 4],
+    pub(crate) lifetime: f32,
+    pub(crate) max_lifetime: f32,
+    pub(crate) emitter: u32,
+    pub(crate) _pad2: u32,
+}
+
+/// Size of a particle in the storage buffer.
+pub const PARTICLE_SIZE: u64 = std::mem::size_of::<Particle>() as u64;
+
+/// Particles are laid out (and stepped) in groups of this many. This and the other sizes the shaders
+/// need are passed to them as `#define`s, see `shader_defines`.
+pub const PARTICLE_GROUP_SIZE: u32 = 256;
+
+/// `sources` with an effect's WGSL standing in for behaviour.wgsl.
+fn with_snippet(sources: &Sources, snippet: &Snippet) -> Sources {
+    let mut sources = sources.clone();
+    sources.insert(shaders::BEHAVIOUR_SHADER_FILE, snippet.with_defaults());
+    sources
+}
+
+/// Points errors in an effect's WGSL at the effect file. As they come out of the compiler they're in
+/// behaviour.wgsl, which means nothing to whoever wrote the effect.
+fn snippet_error(snippet: &Snippet, e: ShaderError) -> ShaderError {
+    let line = |at: &str| -> Option<usize> {
+        at.strip_prefix(shaders::BEHAVIOUR_SHADER_FILE)?
+            .strip_prefix(':')?
+            .parse()
+            .ok()
+    };
+
+    match e {
+        ShaderError::Parse {
+            at,
+            message,
+            diagnostics,
+        } => match line(&at) {
+            // naga's report numbers the lines of the whole preprocessed compute.wgsl, which would only mislead
+            Some(line) => ShaderError::Parse {
+                at: snippet.describe_line(line),
+                message,
+                diagnostics: String::new(),
+            },
+            None => ShaderError::Parse {
+                at,
+                message,
+                diagnostics,
+            },
+        },
+        ShaderError::Validation { at, message } => ShaderError::Validation {
+            at: match line(&at) {
+                Some(line) => snippet.describe_line(line),
+                None => at,
+            },
+            message,
+        },
+        e => e,
+    }
+}
+
+/// `workgroup_size` of `step_particles`, each invocation steps one group.
+const STEP_WORKGROUP_SIZE: u32 = 64;
+
+/// `workgroup_size` of `emit`, each invocation emits one particle.
+const EMIT_WORKGROUP_SIZE: u32 = 64;
+
+/// Emitter counts are per frame at this rate, see `step_frames` in common.wgsl.
+pub const REFERENCE_FPS: f32 = 60.0;
+
+/// How much lifetime a particle loses per (60Hz) frame.
+pub const LIFETIME_PER_FRAME: f32 = 0.16;
+
+/// Size of an entry on the dead and alive lists, a `u32` particle index.
+const INDEX_SIZE: u64 = 4;
+
+/// Each live particle is drawn as one instance of a triangle.
+const VERTICES_PER_PARTICLE: u32 = 3;
+
+/// Picks how many particles to allocate: `requested` (or the default for `simulation`), clamped to what fits in
+/// a single storage buffer binding on this device, rounded down to a whole group.
+pub fn particle_capacity(
+    limits: &wgpu::Limits,
+    simulation: Simulation,
+    requested: Option<u32>,
+) -> u32 {
+    let default = match simulation {
+        Simulation::Gpu => DEFAULT_MAX_PARTICLES,
+        Simulation::Cpu => DEFAULT_CPU_PARTICLES,
+    };
+    let fits = (limits.max_storage_buffer_binding_size as u64 / PARTICLE_SIZE) as u32;
+    let capacity = requested.unwrap_or(default).min(fits);
+
+    (capacity / PARTICLE_GROUP_SIZE).max(1) * PARTICLE_GROUP_SIZE
+}
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
@@ -24,134 +143,930 @@ pub struct Uniforms {
     pub(crate) paused: u32,
     pub(crate) mouse_down: u32,
     pub(crate) mouse_pos_last: [f32; 2],
+    /// Seconds per simulation step.
+    pub(crate) dt: f32,
+    /// Simulated seconds since startup.
+    pub(crate) time: f32,
+    /// Multiplies `dt` in the shaders, 1.0 for real time.
+    pub(crate) time_scale: f32,
+    pub(crate) _pad: u32,
     // make sure we stay 16 byte aligned, especially when using arrays
     // TODO: camera
 }
 
-pub struct RenderStuff {
-    pub shaders: ShaderModule,
-    pub shared: Shared,
-    pub compute: Compute,
-    pub render: Render,
+impl Uniforms {
+    /// Before the first frame: nothing to simulate yet.
+    const INITIAL: Uniforms = Uniforms {
+        paused: 0,
+        mouse_down: 0,
+        mouse_pos_last: [0.0, 0.0],
+        dt: 0.0,
+        time: 0.0,
+        time_scale: 1.0,
+        _pad: 0,
+    };
+
+    pub(crate) fn from_params(params: &UpdateParams) -> Self {
+        Uniforms {
+            paused: params.paused as u32,
+            mouse_down: params.emitting as u32,
+            mouse_pos_last: params.emit_pos,
+            dt: params.dt,
+            time: params.time,
+            time_scale: params.time_scale,
+            _pad: 0,
+        }
+    }
 }
 
-pub struct Compute {
-    pub particle_buffer: Buffer,
+/// How many emitters an effect can have, the size of the array in `EffectParams`.
+pub const MAX_EMITTERS: usize = 16;
 
-    pub bind_group: BindGroup,
-    pub bind_group_layout: BindGroupLayout,
+/// One emitter as the compute shader sees it, built from `effect::Emitter`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct EmitterParams {
+    pub(crate) offset: [f32; 2],
+    pub(crate) count: u32,
+    pub(crate) shape: u32,
+    pub(crate) lifetime: f32,
+    pub(crate) speed: f32,
+    pub(crate) friction: f32,
+    pub(crate) _pad: u32,
+}
 
-    pub emit_pipeline: ComputePipeline,
-    pub compute_pipeline: ComputePipeline,
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct EffectParams {
+    pub(crate) emitter_count: u32,
+    // uniform arrays need to start 16 byte aligned
+    pub(crate) _pad: [u32; 3],
+    pub(crate) emitters: [EmitterParams; MAX_EMITTERS],
 }
 
-impl Compute {
-    fn new(
-        gc: &mut GraphicsContext,
-        shaders: &ShaderModule,
-        shared_bind_group_layout: &BindGroupLayout,
-    ) -> Self {
-        let particle_buffer = gc.device.create_buffer_init(&BufferInitDescriptor {
-            label: Some("Particle Buffer 0"),
-            contents: &vec![0u8; MAX_PARTICLES as usize * 64],
-            usage: BufferUsages::STORAGE | BufferUsages::INDIRECT,
+/// Arguments for `draw_indirect`, in the order wgpu expects them. See `DrawArgs` in compute.wgsl.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct DrawArgs {
+    pub vertex_count: u32,
+    pub instance_count: u32,
+    pub base_vertex: u32,
+    pub base_instance: u32,
+}
+
+impl DrawArgs {
+    /// No particles to draw, what `reset_alive_list` gets back to.
+    const EMPTY: DrawArgs = DrawArgs {
+        vertex_count: VERTICES_PER_PARTICLE,
+        instance_count: 0,
+        base_vertex: 0,
+        base_instance: 0,
+    };
+}
+
+/// Where the particles get simulated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Simulation {
+    /// The compute passes in compute.wgsl, the normal way.
+    Gpu,
+    /// `CpuSim`, uploading the results every step. For devices that can't do compute, and to check the
+    /// shaders against.
+    Cpu,
+}
+
+impl Simulation {
+    /// The GPU if the device can do compute, otherwise the CPU. `None` if it can't do either: both draw the
+    /// particles straight out of a storage buffer, which GL ES 3.0 and WebGL2 can't read in a vertex shader.
+    pub fn supported_by(downlevel: &wgpu::DownlevelCapabilities) -> Option<Self> {
+        let flags = downlevel.flags;
+        if !flags.contains(wgpu::DownlevelFlags::VERTEX_STORAGE) {
+            None
+        } else if flags.contains(wgpu::DownlevelFlags::COMPUTE_SHADERS) {
+            Some(Simulation::Gpu)
+        } else {
+            Some(Simulation::Cpu)
+        }
+    }
+}
+
+/// How to set up a `ParticleSystem`, apart from the device and the format it draws into.
+#[derive(Debug, Clone)]
+pub struct ParticleSystemConfig {
+    /// How many particles to make room for, `None` for the default. Either way it's clamped to what
+    /// the device can hold, see `particle_capacity`.
+    pub capacity: Option<u32>,
+    /// The emitters, and how the particles blend with what's under them.
+    pub effect: Effect,
+    /// What each particle looks like.
+    pub texture: ParticleTexture,
+    /// `Simulation::supported_by` the adapter's downlevel capabilities picks one that'll work.
+    /// Systems made `with_pipelines` go with the pipelines' instead.
+    pub simulation: Simulation,
+    /// Of the render passes `ParticleSystem::draw` is recorded into. Systems made `with_pipelines` go with
+    /// the pipelines' instead.
+    pub sample_count: u32,
+}
+
+impl Default for ParticleSystemConfig {
+    fn default() -> Self {
+        ParticleSystemConfig {
+            capacity: None,
+            effect: Effect::default(),
+            texture: ParticleTexture::built_in(),
+            simulation: Simulation::Gpu,
+            sample_count: 1,
+        }
+    }
+}
+
+/// What a `ParticleSystem::update` step needs from the host.
+#[derive(Debug, Clone, Copy)]
+pub struct UpdateParams {
+    /// Where the effect's emitters are, in clip space. The demo uses the mouse.
+    pub emit_pos: [f32; 2],
+    /// Whether to emit this step.
+    pub emitting: bool,
+    /// Seconds per step.
+    pub dt: f32,
+    /// Simulated seconds since startup.
+    pub time: f32,
+    /// Multiplies `dt`, 1.0 for real time.
+    pub time_scale: f32,
+    /// Paused steps leave the particles as they are, but they still get drawn.
+    pub paused: bool,
+}
+
+/// A texture for `ParticleSystem::render` to draw into. Its format and sample count have to be what the
+/// system's pipelines were built for.
+#[derive(Clone, Copy)]
+pub struct ColorTarget<'a> {
+    /// Where the particles end up.
+    pub view: &'a wgpu::TextureView,
+    /// With a sample count above 1, the multisampled texture that's drawn into and then resolved into `view`.
+    pub msaa_view: Option<&'a wgpu::TextureView>,
+    /// `Clear` to start from scratch, `Load` to draw over what's already there (a scene, say).
+    /// With MSAA this applies to `msaa_view`, so that's what has to hold the scene.
+    pub load: wgpu::LoadOp<wgpu::Color>,
+}
+
+impl<'a> ColorTarget<'a> {
+    /// Begins a render pass into the target, for drawing several systems (or anything else) in one go.
+    pub fn begin_render_pass<'e>(&self, encoder: &'e mut wgpu::CommandEncoder) -> RenderPass<'e>
+    where
+        'a: 'e,
+    {
+        // with MSAA the particles are drawn multisampled, then resolved into the caller's view
+        let (view, resolve_target) = match self.msaa_view {
+            Some(msaa_view) => (msaa_view, Some(self.view)),
+            None => (self.view, None),
+        };
+
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("particle render pass"),
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target,
+                ops: wgpu::Operations {
+                    load: self.load,
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        })
+    }
+}
+
+/// A pool of particles, simulated and drawn with one effect. Everything's recorded into command encoders and
+/// render passes the caller owns, so it can be embedded in any renderer using the same device.
+pub struct ParticleSystem {
+    pub(crate) capacity: u32,
+    /// What's in `shared.effect`, kept around to size the emission dispatch.
+    pub(crate) effect_params: EffectParams,
+    /// What's in `shared.uniforms`, for the CPU simulation.
+    pub(crate) uniforms: Uniforms,
+    /// Possibly shared with other systems, see `with_pipelines`.
+    pub(crate) pipelines: Arc<Pipelines>,
+    pub(crate) shared: Shared,
+    pub(crate) compute: Compute,
+    pub(crate) render: Render,
+    /// The compute passes run every step, in order.
+    pub(crate) stages: Vec<Box<dyn ParticleStage>>,
+    /// Does the compute passes' job when we're simulating on the CPU.
+    pub(crate) cpu_sim: Option<CpuSim>,
+}
+
+/// The bind group layouts the shaders are written against. Every system's bind groups are made with the same
+/// ones, which is what lets systems share `Pipelines`.
+pub struct Layouts {
+    /// Group 0 of compute.wgsl: the particles, both lists and the draw args.
+    pub compute: BindGroupLayout,
+    /// Group 1 of compute.wgsl: helper data, uniforms and the effect.
+    pub shared_compute: BindGroupLayout,
+    /// Group 0 of frag_vert.wgsl: the particles and the alive list.
+    pub render: BindGroupLayout,
+    /// Group 1 of frag_vert.wgsl: helper data and uniforms.
+    pub shared_render: BindGroupLayout,
+    /// Group 2 of frag_vert.wgsl: the particle texture.
+    pub texture: BindGroupLayout,
+}
+
+impl Layouts {
+    fn new(device: &wgpu::Device) -> Self {
+        let compute = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
         });
 
-        let compute_bind_group_layout =
-            gc.device
-                .create_bind_group_layout(&BindGroupLayoutDescriptor {
-                    label: None,
-                    entries: &[BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: ShaderStages::COMPUTE,
-                        ty: BindingType::Buffer {
-                            ty: BufferBindingType::Storage { read_only: false },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    }],
-                });
+        let shared_compute = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::all(),
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
 
-        let compute_bind_group = gc.device.create_bind_group(&BindGroupDescriptor {
+        let render = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
             label: None,
-            layout: &compute_bind_group_layout,
-            entries: &[BindGroupEntry {
-                binding: 0,
-                resource: particle_buffer.as_entire_binding(),
-            }],
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::VERTEX,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::VERTEX,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
         });
 
-        let compute_pipeline = gc
-            .device
-            .create_compute_pipeline(&ComputePipelineDescriptor {
-                label: None,
-                layout: Some(
-                    &gc.device.create_pipeline_layout(&PipelineLayoutDescriptor {
-                        label: None,
-                        bind_group_layouts: &[
-                            &compute_bind_group_layout,
-                            &shared_bind_group_layout,
-                        ],
-                        push_constant_ranges: &[],
-                    }),
-                ),
-                module: &shaders,
-                entry_point: "step_particles",
-            });
-
-        let emit_pipeline = gc
-            .device
-            .create_compute_pipeline(&ComputePipelineDescriptor {
+        let shared_render = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::all(),
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::all(),
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let texture = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Uint,
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Sampler(SamplerBindingType::NonFiltering),
+                    count: None,
+                },
+            ],
+        });
+
+        Layouts {
+            compute,
+            shared_compute,
+            render,
+            shared_render,
+            texture,
+        }
+    }
+}
+
+/// Everything built from the shaders. Any number of systems drawing into the same kind of target can share one,
+/// each blend mode has its own render pipeline.
+pub struct Pipelines {
+    pub layouts: Arc<Layouts>,
+    /// What the shaders were built from, kept for `retarget`.
+    sources: Sources,
+    /// An effect's hooks, in place of behaviour.wgsl. See `for_effect`.
+    behaviour: Option<Snippet>,
+    simulation: Simulation,
+    format: TextureFormat,
+    sample_count: u32,
+    /// `None` when simulating on the CPU, which skips compute.wgsl altogether.
+    pub compute: Option<ComputePipelines>,
+    /// One for each of `BlendMode::ALL`, in that order.
+    render: Vec<RenderPipeline>,
+}
+
+/// Everything built from compute.wgsl, see the entry points there.
+pub struct ComputePipelines {
+    pub reset_alive_list: ComputePipeline,
+    pub step: ComputePipeline,
+    pub emit: ComputePipeline,
+    pub rebuild_lists: ComputePipeline,
+}
+
+impl Pipelines {
+    /// Pipelines from the built-in shaders, drawing into `format` targets with `sample_count` samples.
+    pub fn new(
+        device: &wgpu::Device,
+        format: TextureFormat,
+        sample_count: u32,
+        simulation: Simulation,
+    ) -> Self {
+        Self::build(
+            device,
+            Arc::new(Layouts::new(device)),
+            Sources::built_in(),
+            None,
+            simulation,
+            format,
+            sample_count,
+        )
+        .expect("built-in shaders are broken")
+    }
+
+    /// The same pipelines, rebuilt from new shader source. Systems using these can switch to the new ones with
+    /// `ParticleSystem::set_pipelines`.
+    pub fn reload(&self, device: &wgpu::Device, sources: &Sources) -> Result<Self, ShaderError> {
+        Self::build(
+            device,
+            self.layouts.clone(),
+            sources.clone(),
+            self.behaviour.clone(),
+            self.simulation,
+            self.format,
+            self.sample_count,
+        )
+    }
+
+    /// The same pipelines, for targets of a different format or sample count.
+    pub fn retarget(
+        &self,
+        device: &wgpu::Device,
+        format: TextureFormat,
+        sample_count: u32,
+    ) -> Result<Self, ShaderError> {
+        Self::build(
+            device,
+            self.layouts.clone(),
+            self.sources.clone(),
+            self.behaviour.clone(),
+            self.simulation,
+            format,
+            sample_count,
+        )
+    }
+
+    /// Pipelines running `effect`'s WGSL hooks: these if they already do, otherwise the same ones rebuilt
+    /// with its hooks spliced in. Errors in the hooks point at the effect's lines. The hooks only run on
+    /// the GPU, so with the CPU simulation these are always fine as they are.
+    pub fn for_effect(
+        self: &Arc<Self>,
+        device: &wgpu::Device,
+        effect: &Effect,
+    ) -> Result<Arc<Self>, ShaderError> {
+        if self.behaviour == effect.wgsl || self.simulation == Simulation::Cpu {
+            return Ok(self.clone());
+        }
+
+        let pipelines = Self::build(
+            device,
+            self.layouts.clone(),
+            self.sources.clone(),
+            effect.wgsl.clone(),
+            self.simulation,
+            self.format,
+            self.sample_count,
+        )?;
+        Ok(Arc::new(pipelines))
+    }
+
+    pub fn simulation(&self) -> Simulation {
+        self.simulation
+    }
+
+    /// What the shaders were built from, for compiling more against them (see `WgslStage`).
+    pub fn sources(&self) -> &Sources {
+        &self.sources
+    }
+
+    pub fn format(&self) -> TextureFormat {
+        self.format
+    }
+
+    pub fn sample_count(&self) -> u32 {
+        self.sample_count
+    }
+
+    fn render_pipeline(&self, blend: BlendMode) -> &RenderPipeline {
+        &self.render[blend as usize]
+    }
+
+    fn build(
+        device: &wgpu::Device,
+        layouts: Arc<Layouts>,
+        sources: Sources,
+        behaviour: Option<Snippet>,
+        simulation: Simulation,
+        format: TextureFormat,
+        sample_count: u32,
+    ) -> Result<Self, ShaderError> {
+        let defines = shader_defines();
+        let compute_shaders = match simulation {
+            Simulation::Gpu => Some(Self::compile_compute(
+                device,
+                &sources,
+                behaviour.as_ref(),
+                &defines,
+            )?),
+            Simulation::Cpu => None,
+        };
+        let render_shaders =
+            shaders::compile(device, &sources, shaders::RENDER_SHADER_FILE, &defines)?;
+
+        let (compute, render) = shaders::catch_pipeline_errors(device, || {
+            let compute = compute_shaders
+                .as_ref()
+                .map(|shaders| Self::create_compute_pipelines(device, shaders, &layouts));
+            let render = BlendMode::ALL
+                .iter()
+                .map(|&blend| {
+                    Self::create_render_pipeline(
+                        device,
+                        &render_shaders,
+                        &layouts,
+                        format,
+                        sample_count,
+                        blend,
+                    )
+                })
+                .collect();
+            (compute, render)
+        })?;
+
+        Ok(Pipelines {
+            layouts,
+            sources,
+            behaviour,
+            simulation,
+            format,
+            sample_count,
+            compute,
+            render,
+        })
+    }
+
+    /// compute.wgsl, with `behaviour` standing in for behaviour.wgsl if there is one.
+    fn compile_compute(
+        device: &wgpu::Device,
+        sources: &Sources,
+        behaviour: Option<&Snippet>,
+        defines: &[(&str, String)],
+    ) -> Result<ShaderModule, ShaderError> {
+        match behaviour {
+            None => shaders::compile(device, sources, shaders::COMPUTE_SHADER_FILE, defines),
+            Some(snippet) => shaders::compile(
+                device,
+                &with_snippet(sources, snippet),
+                shaders::COMPUTE_SHADER_FILE,
+                defines,
+            )
+            .map_err(|e| snippet_error(snippet, e)),
+        }
+    }
+
+    fn create_compute_pipelines(
+        device: &wgpu::Device,
+        shaders: &ShaderModule,
+        layouts: &Layouts,
+    ) -> ComputePipelines {
+        let layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&layouts.compute, &layouts.shared_compute],
+            push_constant_ranges: &[],
+        });
+
+        let create = |entry_point| {
+            device.create_compute_pipeline(&ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&layout),
+                module: shaders,
+                entry_point,
+            })
+        };
+
+        ComputePipelines {
+            reset_alive_list: create("reset_alive_list"),
+            step: create("step_particles"),
+            emit: create("emit"),
+            rebuild_lists: create("rebuild_lists"),
+        }
+    }
+
+    fn create_render_pipeline(
+        device: &wgpu::Device,
+        shaders: &ShaderModule,
+        layouts: &Layouts,
+        format: TextureFormat,
+        sample_count: u32,
+        blend: BlendMode,
+    ) -> RenderPipeline {
+        device.create_render_pipeline(&RenderPipelineDescriptor {
+            label: None,
+            layout: Some(&device.create_pipeline_layout(&PipelineLayoutDescriptor {
                 label: None,
-                layout: Some(
-                    &gc.device.create_pipeline_layout(&PipelineLayoutDescriptor {
-                        label: None,
-                        bind_group_layouts: &[
-                            &compute_bind_group_layout,
-                            &shared_bind_group_layout,
-                        ],
-                        push_constant_ranges: &[],
-                    }),
-                ),
-                module: &shaders,
-                entry_point: "emit",
-            });
+                bind_group_layouts: &[&layouts.render, &layouts.shared_render, &layouts.texture],
+                push_constant_ranges: &[],
+            })),
+            vertex: VertexState {
+                module: shaders,
+                entry_point: "main",
+                buffers: &[],
+            },
+            primitive: PrimitiveState {
+                topology: PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: FrontFace::Ccw,
+                cull_mode: None,
+                unclipped_depth: false,
+                polygon_mode: PolygonMode::Fill,
+                conservative: false,
+            },
+            depth_stencil: None,
+            multisample: MultisampleState {
+                count: sample_count,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            fragment: Some(FragmentState {
+                module: shaders,
+                entry_point: "main",
+                targets: &[ColorTargetState {
+                    blend: Some(blend_state(blend)),
+                    ..format.into()
+                }],
+            }),
+            multiview: None,
+        })
+    }
+}
+
+fn blend_state(blend: BlendMode) -> BlendState {
+    match blend {
+        // scaled by the destination's alpha too, which is 1 over anything opaque
+        BlendMode::Additive => BlendState {
+            color: BlendComponent {
+                src_factor: BlendFactor::SrcAlpha,
+                dst_factor: BlendFactor::DstAlpha,
+                operation: BlendOperation::Add,
+            },
+            alpha: BlendComponent::OVER,
+        },
+        BlendMode::Alpha => BlendState::ALPHA_BLENDING,
+    }
+}
+
+pub struct Compute {
+    pub particle_buffer: Buffer,
+    /// Stack of dead particle indices, its length is `deadCount` in `HelperData`.
+    pub dead_list: Buffer,
+    /// Indices of the live particles, its length is the instance count in `draw_args`.
+    pub alive_list: Buffer,
+    /// `DrawArgs` for the render pass, filled in by the compute shaders.
+    pub draw_args: Buffer,
+
+    pub bind_group: BindGroup,
+}
+
+impl Compute {
+    fn new(device: &wgpu::Device, layouts: &Layouts, capacity: u32) -> Self {
+        let particle_buffer = Self::create_particle_buffer(device, capacity);
+        let dead_list = Self::create_index_list(device, "dead particle indices", capacity);
+        let alive_list = Self::create_index_list(device, "live particle indices", capacity);
+
+        // the compute shaders only ever touch the instance count, stats.rs reads it back
+        let draw_args = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("particle draw args"),
+            contents: bytemuck::bytes_of(&DrawArgs::EMPTY),
+            usage: BufferUsages::STORAGE
+                | BufferUsages::INDIRECT
+                | BufferUsages::COPY_SRC
+                | BufferUsages::COPY_DST,
+        });
+
+        let bind_group = Self::create_bind_group(
+            device,
+            &layouts.compute,
+            &particle_buffer,
+            &dead_list,
+            &alive_list,
+            &draw_args,
+        );
 
         Compute {
             particle_buffer,
-            bind_group: compute_bind_group,
-            bind_group_layout: compute_bind_group_layout,
-            emit_pipeline,
-            compute_pipeline,
+            dead_list,
+            alive_list,
+            draw_args,
+            bind_group,
         }
     }
+
+    /// Swaps in a particle buffer of a different size. The first `min(old, new)` particles are
+    /// copied across on the GPU (anything past the end is dropped when shrinking), the new tail starts out dead.
+    /// The dead and alive lists need rebuilding afterwards, see `rebuild_lists`.
+    fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        layouts: &Layouts,
+        encoder: &mut wgpu::CommandEncoder,
+        old_capacity: u32,
+        capacity: u32,
+    ) {
+        let particle_buffer = Self::create_particle_buffer(device, capacity);
+
+        encoder.copy_buffer_to_buffer(
+            &self.particle_buffer,
+            0,
+            &particle_buffer,
+            0,
+            old_capacity.min(capacity) as u64 * PARTICLE_SIZE,
+        );
+
+        self.dead_list = Self::create_index_list(device, "dead particle indices", capacity);
+        self.alive_list = Self::create_index_list(device, "live particle indices", capacity);
+        self.bind_group = Self::create_bind_group(
+            device,
+            &layouts.compute,
+            &particle_buffer,
+            &self.dead_list,
+            &self.alive_list,
+            &self.draw_args,
+        );
+        self.particle_buffer = particle_buffer;
+    }
+
+    /// Sorts every particle onto the (empty) dead or alive list.
+    /// `deadCount` and the instance count in `draw_args` have to be zeroed first.
+    fn rebuild_lists(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        pipelines: &Pipelines,
+        shared_bind_group: &BindGroup,
+        capacity: u32,
+    ) {
+        let pipelines = match &pipelines.compute {
+            Some(pipelines) => pipelines,
+            None => return,
+        };
+
+        let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("rebuild particle lists"),
+        });
+        cpass.set_pipeline(&pipelines.rebuild_lists);
+        cpass.set_bind_group(0, &self.bind_group, &[]);
+        cpass.set_bind_group(1, shared_bind_group, &[]);
+        cpass.dispatch(Self::step_workgroups(capacity), 1, 1);
+    }
+
+    fn create_particle_buffer(device: &wgpu::Device, capacity: u32) -> Buffer {
+        // new buffers are zeroed, and a zero lifetime is a dead particle
+        device.create_buffer(&BufferDescriptor {
+            label: Some("Particle Buffer 0"),
+            size: capacity as u64 * PARTICLE_SIZE,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn create_index_list(device: &wgpu::Device, label: &str, capacity: u32) -> Buffer {
+        device.create_buffer(&BufferDescriptor {
+            label: Some(label),
+            size: capacity as u64 * INDEX_SIZE,
+            // written directly when simulating on the CPU
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &BindGroupLayout,
+        particle_buffer: &Buffer,
+        dead_list: &Buffer,
+        alive_list: &Buffer,
+        draw_args: &Buffer,
+    ) -> BindGroup {
+        device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: particle_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: dead_list.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: alive_list.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: draw_args.as_entire_binding(),
+                },
+            ],
+        })
+    }
+
+    /// Number of workgroups `step_particles` needs to cover `capacity` particles.
+    pub fn step_workgroups(capacity: u32) -> u32 {
+        let groups = capacity.div_ceil(PARTICLE_GROUP_SIZE);
+        groups.div_ceil(STEP_WORKGROUP_SIZE)
+    }
+
+    /// Workgroups for `emit` to cover every emitter in `effect`, for a step of `dt` (scaled) seconds.
+    /// `emit` works out the exact count itself, this just has to be enough. No emitter can spawn more than
+    /// `capacity` particles, so that's as far as it goes however long the step, which keeps it well within
+    /// `max_compute_workgroups_per_dimension`.
+    pub fn emit_workgroups(effect: &EffectParams, dt: f32, capacity: u32) -> (u32, u32) {
+        let emitters = &effect.emitters[..effect.emitter_count as usize];
+        let frames = dt * REFERENCE_FPS;
+
+        let most = emitters
+            .iter()
+            .map(|emitter| ((emitter.count as f32 * frames).ceil() as u32).saturating_add(1))
+            .max()
+            .unwrap_or(0)
+            .min(capacity);
+
+        (most.div_ceil(EMIT_WORKGROUP_SIZE), effect.emitter_count)
+    }
 }
 
 pub struct Render {
-    pub render_pipeline: RenderPipeline,
     pub bind_group: BindGroup,
     pub texture_bind_group: BindGroup,
+    /// Picks which of the shared render pipelines draws this system.
+    pub blend: BlendMode,
 }
 
 impl Render {
     fn new(
-        gc: &mut GraphicsContext,
-        shaders: &ShaderModule,
-        shared_render_bgl: &BindGroupLayout,
-        particle_buffer: &Buffer,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layouts: &Layouts,
+        compute: &Compute,
+        texture: &ParticleTexture,
+        blend: BlendMode,
     ) -> Self {
-        let diffuse_bytes = include_bytes!("particle.png");
-        let diffuse_image = image::load_from_memory(diffuse_bytes).unwrap();
-        let diffuse_r = diffuse_image.as_luma8().unwrap();
+        Render {
+            bind_group: Self::create_bind_group(device, &layouts.render, compute),
+            texture_bind_group: Self::create_texture_bind_group(
+                device,
+                queue,
+                &layouts.texture,
+                texture,
+            ),
+            blend,
+        }
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &BindGroupLayout,
+        compute: &Compute,
+    ) -> BindGroup {
+        device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: compute.particle_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: compute.alive_list.as_entire_binding(),
+                },
+            ],
+        })
+    }
 
+    /// Uploads `texture` and binds it, the fragment shader reads it with `textureLoad`.
+    fn create_texture_bind_group(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &BindGroupLayout,
+        texture: &ParticleTexture,
+    ) -> BindGroup {
         let texture_extent = wgpu::Extent3d {
-            width: 512,
-            height: 512,
+            width: texture.width,
+            height: texture.height,
             depth_or_array_layers: 1,
         };
 
-        let texture = gc.device.create_texture(&wgpu::TextureDescriptor {
+        let gpu_texture = device.create_texture(&wgpu::TextureDescriptor {
             label: None,
             size: texture_extent,
             mip_level_count: 1,
@@ -160,21 +1075,20 @@ impl Render {
             format: wgpu::TextureFormat::R8Uint,
             usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
         });
+        let texture_view = gpu_texture.create_view(&wgpu::TextureViewDescriptor::default());
 
-        let texture_view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-
-        gc.queue.write_texture(
-            texture.as_image_copy(),
-            diffuse_r,
+        queue.write_texture(
+            gpu_texture.as_image_copy(),
+            &texture.pixels,
             wgpu::ImageDataLayout {
                 offset: 0,
-                bytes_per_row: Some(std::num::NonZeroU32::new(512).unwrap()),
+                bytes_per_row: std::num::NonZeroU32::new(texture.width),
                 rows_per_image: None,
             },
             texture_extent,
         );
 
-        let texture_sampler = gc.device.create_sampler(&wgpu::SamplerDescriptor {
+        let texture_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
             address_mode_u: wgpu::AddressMode::ClampToEdge,
             address_mode_v: wgpu::AddressMode::ClampToEdge,
             address_mode_w: wgpu::AddressMode::ClampToEdge,
@@ -184,32 +1098,8 @@ impl Render {
             ..Default::default()
         });
 
-        let texture_bind_group_layout =
-            gc.device
-                .create_bind_group_layout(&BindGroupLayoutDescriptor {
-                    label: None,
-                    entries: &[
-                        BindGroupLayoutEntry {
-                            binding: 0,
-                            visibility: ShaderStages::FRAGMENT,
-                            ty: BindingType::Texture {
-                                sample_type: TextureSampleType::Uint,
-                                view_dimension: TextureViewDimension::D2,
-                                multisampled: false,
-                            },
-                            count: None,
-                        },
-                        BindGroupLayoutEntry {
-                            binding: 1,
-                            visibility: ShaderStages::FRAGMENT,
-                            ty: BindingType::Sampler(SamplerBindingType::NonFiltering),
-                            count: None,
-                        },
-                    ],
-                });
-
-        let texture_bind_group = gc.device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &texture_bind_group_layout,
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
@@ -221,180 +1111,44 @@ impl Render {
                 },
             ],
             label: Some("diffuse_bind_group"),
-        });
-
-        let render_bind_group_layout =
-            gc.device
-                .create_bind_group_layout(&BindGroupLayoutDescriptor {
-                    label: None,
-                    entries: &[BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: ShaderStages::VERTEX,
-                        ty: BindingType::Buffer {
-                            ty: BufferBindingType::Storage { read_only: true },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    }],
-                });
-
-        let bind_group = gc.device.create_bind_group(&BindGroupDescriptor {
-            label: None,
-            layout: &render_bind_group_layout,
-            entries: &[BindGroupEntry {
-                binding: 0,
-                resource: particle_buffer.as_entire_binding(),
-            }],
-        });
-
-        let render_pipeline = gc.device.create_render_pipeline(&RenderPipelineDescriptor {
-            label: None,
-            layout: Some(
-                &gc.device.create_pipeline_layout(&PipelineLayoutDescriptor {
-                    label: None,
-                    bind_group_layouts: &[
-                        &render_bind_group_layout,
-                        &shared_render_bgl,
-                        &texture_bind_group_layout,
-                    ],
-                    push_constant_ranges: &[],
-                }),
-            ),
-            vertex: VertexState {
-                module: &shaders,
-                entry_point: "main",
-                buffers: &[],
-            },
-            primitive: PrimitiveState {
-                topology: PrimitiveTopology::TriangleList,
-                strip_index_format: None,
-                front_face: FrontFace::Ccw,
-                cull_mode: None,
-                unclipped_depth: false,
-                polygon_mode: PolygonMode::Fill,
-                conservative: false,
-            },
-            depth_stencil: None,
-            multisample: MultisampleState {
-                count: gc.sample_count,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
-            },
-            fragment: Some(FragmentState {
-                module: &shaders,
-                entry_point: "main",
-                targets: &[ColorTargetState {
-                    blend: Some(BlendState {
-                        color: BlendComponent {
-                            src_factor: BlendFactor::SrcAlpha,
-                            dst_factor: BlendFactor::DstAlpha,
-                            operation: BlendOperation::Add,
-                        },
-                        alpha: BlendComponent::OVER,
-                    }),
-                    ..gc.config.format.into()
-                }],
-            }),
-            multiview: None,
-        });
-
-        Render {
-            render_pipeline,
-            bind_group,
-            texture_bind_group,
-        }
+        })
     }
 }
 
 pub struct Shared {
     pub helper_data: Buffer,
     pub uniforms: Buffer,
-    pub compute_bind_layout: BindGroupLayout,
-    pub render_bind_layout: BindGroupLayout,
+    pub effect: Buffer,
     pub compute_bind_group: BindGroup,
     pub render_bind_group: BindGroup,
 }
 
 impl Shared {
-    fn new(gc: &mut GraphicsContext) -> Self {
-        let helper_data = gc.device.create_buffer_init(&BufferInitDescriptor {
+    fn new(device: &wgpu::Device, layouts: &Layouts, capacity: u32, effect: &Effect) -> Self {
+        let helper_data = device.create_buffer_init(&BufferInitDescriptor {
             label: Some("helper data for compute shaders"),
-            usage: BufferUsages::STORAGE | BufferUsages::INDIRECT,
+            usage: BufferUsages::STORAGE | BufferUsages::INDIRECT | BufferUsages::COPY_DST,
             contents: bytemuck::bytes_of(&HelperData {
-                max_particles: MAX_PARTICLES,
+                max_particles: capacity,
+                dead_count: 0,
             }),
         });
 
-        let uniforms = gc.device.create_buffer_init(&BufferInitDescriptor {
+        let uniforms = device.create_buffer_init(&BufferInitDescriptor {
             label: Some("camera + mouse data uniforms"),
-            contents: bytemuck::cast_slice(&[Uniforms {
-                paused: 0,
-                mouse_down: 0,
-                mouse_pos_last: [0.0, 0.0],
-            }]),
+            contents: bytemuck::bytes_of(&Uniforms::INITIAL),
             usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
         });
 
-        let shared_compute_bind_layout =
-            gc.device
-                .create_bind_group_layout(&BindGroupLayoutDescriptor {
-                    label: None,
-                    entries: &[
-                        BindGroupLayoutEntry {
-                            binding: 0,
-                            visibility: ShaderStages::COMPUTE,
-                            ty: BindingType::Buffer {
-                                ty: BufferBindingType::Storage { read_only: false },
-                                has_dynamic_offset: false,
-                                min_binding_size: None,
-                            },
-                            count: None,
-                        },
-                        BindGroupLayoutEntry {
-                            binding: 1,
-                            visibility: ShaderStages::all(),
-                            ty: BindingType::Buffer {
-                                ty: BufferBindingType::Uniform,
-                                has_dynamic_offset: false,
-                                min_binding_size: None,
-                            },
-                            count: None,
-                        },
-                    ],
-                });
-
-        let shared_render_bind_layout =
-            gc.device
-                .create_bind_group_layout(&BindGroupLayoutDescriptor {
-                    label: None,
-                    entries: &[
-                        BindGroupLayoutEntry {
-                            binding: 0,
-                            visibility: ShaderStages::all(),
-                            ty: BindingType::Buffer {
-                                ty: BufferBindingType::Storage { read_only: true },
-                                has_dynamic_offset: false,
-                                min_binding_size: None,
-                            },
-                            count: None,
-                        },
-                        BindGroupLayoutEntry {
-                            binding: 1,
-                            visibility: ShaderStages::all(),
-                            ty: BindingType::Buffer {
-                                ty: BufferBindingType::Uniform,
-                                has_dynamic_offset: false,
-                                min_binding_size: None,
-                            },
-                            count: None,
-                        },
-                    ],
-                });
+        let effect = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("effect parameters"),
+            contents: bytemuck::bytes_of(&effect.to_params()),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        });
 
-        let compute_bind_group = gc.device.create_bind_group(&BindGroupDescriptor {
+        let compute_bind_group = device.create_bind_group(&BindGroupDescriptor {
             label: None,
-            layout: &shared_compute_bind_layout,
+            layout: &layouts.shared_compute,
             entries: &[
                 BindGroupEntry {
                     binding: 0,
@@ -404,12 +1158,16 @@ impl Shared {
                     binding: 1,
                     resource: uniforms.as_entire_binding(),
                 },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: effect.as_entire_binding(),
+                },
             ],
         });
 
-        let render_bind_group = gc.device.create_bind_group(&BindGroupDescriptor {
+        let render_bind_group = device.create_bind_group(&BindGroupDescriptor {
             label: None,
-            layout: &shared_render_bind_layout,
+            layout: &layouts.shared_render,
             entries: &[
                 BindGroupEntry {
                     binding: 0,
@@ -425,47 +1183,591 @@ impl Shared {
         Shared {
             helper_data,
             uniforms,
-            compute_bind_layout: shared_compute_bind_layout,
-            render_bind_layout: shared_render_bind_layout,
+            effect,
             compute_bind_group,
             render_bind_group,
         }
     }
 }
 
+/// `#define`d for every shader, so the sizes they hard-code can't drift from the ones here.
+pub(crate) fn shader_defines() -> Vec<(&'static str, String)> {
+    vec![
+        ("PARTICLE_GROUP_SIZE", format!("{}u", PARTICLE_GROUP_SIZE)),
+        ("STEP_WORKGROUP_SIZE", format!("{}u", STEP_WORKGROUP_SIZE)),
+        ("EMIT_WORKGROUP_SIZE", format!("{}u", EMIT_WORKGROUP_SIZE)),
+        ("REFERENCE_FPS", format!("{:?}", REFERENCE_FPS)),
+        ("LIFETIME_PER_FRAME", format!("{:?}", LIFETIME_PER_FRAME)),
+    ]
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 struct HelperData {
     max_particles: u32,
+    dead_count: i32,
 }
 
-impl RenderStuff {
-    pub fn new(gc: &mut GraphicsContext) -> RenderStuff {
-        let compute_shaders = gc.device.create_shader_module(&ShaderModuleDescriptor {
-            label: Some("particle demo shaders"),
-            source: ShaderSource::Wgsl(include_str!("compute.wgsl").into()),
-        });
-
-        let render_shaders = gc.device.create_shader_module(&ShaderModuleDescriptor {
-            label: Some("particle demo shaders"),
-            source: ShaderSource::Wgsl(include_str!("frag_vert.wgsl").into()),
-        });
+impl ParticleSystem {
+    /// Sets up a system with pipelines of its own, drawing into `format` targets and simulating on
+    /// `config.simulation`. The capacity is clamped to what `device` can hold, see `particle_capacity`.
+    /// Fails if the effect's WGSL hooks don't compile.
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        format: TextureFormat,
+        config: &ParticleSystemConfig,
+    ) -> Result<ParticleSystem, ShaderError> {
+        let pipelines = Pipelines::new(device, format, config.sample_count, config.simulation);
+        Self::with_pipelines(device, queue, Arc::new(pipelines), config)
+    }
 
-        let shared = Shared::new(gc);
-        let compute = Compute::new(gc, &compute_shaders, &shared.compute_bind_layout);
+    /// Sets up a system that shares `pipelines` with any others made from them. The simulation and the target's
+    /// format and sample count are the pipelines', `config`'s are ignored. An effect with WGSL hooks gets
+    /// pipelines of its own (see `Pipelines::for_effect`), and fails if they don't compile.
+    pub fn with_pipelines(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: Arc<Pipelines>,
+        config: &ParticleSystemConfig,
+    ) -> Result<ParticleSystem, ShaderError> {
+        let pipelines = pipelines.for_effect(device, &config.effect)?;
+        let simulation = pipelines.simulation();
+        let capacity = particle_capacity(&device.limits(), simulation, config.capacity);
+        let layouts = &pipelines.layouts;
 
+        let shared = Shared::new(device, layouts, capacity, &config.effect);
+        let compute = Compute::new(device, layouts, capacity);
         let render = Render::new(
-            gc,
-            &render_shaders,
-            &shared.render_bind_layout,
-            &compute.particle_buffer,
+            device,
+            queue,
+            layouts,
+            &compute,
+            &config.texture,
+            config.effect.blend,
+        );
+
+        // everything starts out dead, so the whole buffer goes on the dead list (and none on the alive one)
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("initial dead list"),
+        });
+        compute.rebuild_lists(
+            &mut encoder,
+            &pipelines,
+            &shared.compute_bind_group,
+            capacity,
         );
+        queue.submit(Some(encoder.finish()));
 
-        RenderStuff {
-            shaders: compute_shaders,
+        Ok(ParticleSystem {
+            capacity,
+            effect_params: config.effect.to_params(),
+            uniforms: Uniforms::INITIAL,
+            pipelines,
             shared,
             compute,
             render,
+            stages: stages::default_stages(),
+            cpu_sim: match simulation {
+                Simulation::Gpu => None,
+                Simulation::Cpu => Some(CpuSim::new(capacity)),
+            },
+        })
+    }
+
+    /// How many particles there's room for, live or dead.
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    pub fn simulation(&self) -> Simulation {
+        self.pipelines.simulation()
+    }
+
+    /// `DrawArgs` for the particles, the instance count is how many are alive once the recorded steps have run.
+    pub fn draw_args(&self) -> &Buffer {
+        &self.compute.draw_args
+    }
+
+    /// The compute passes run every step, `Physics` then `Emission` to begin with.
+    pub fn stages(&self) -> &[Box<dyn ParticleStage>] {
+        &self.stages
+    }
+
+    /// For adding, removing or reordering stages. They're skipped when simulating on the CPU.
+    pub fn stages_mut(&mut self) -> &mut Vec<Box<dyn ParticleStage>> {
+        &mut self.stages
+    }
+
+    /// Runs `stage` after the ones already there.
+    pub fn add_stage(&mut self, stage: impl ParticleStage + 'static) {
+        self.stages.push(Box::new(stage));
+    }
+
+    pub fn pipelines(&self) -> &Arc<Pipelines> {
+        &self.pipelines
+    }
+
+    /// Switches to other pipelines, from `Pipelines::reload` or `retarget` on this system's (which keep its
+    /// effect's hooks, unlike pipelines from anywhere else).
+    /// Panics if they're for a different simulation, or weren't made from this system's pipelines.
+    pub fn set_pipelines(&mut self, pipelines: Arc<Pipelines>) {
+        assert!(
+            Arc::ptr_eq(&self.pipelines.layouts, &pipelines.layouts),
+            "pipelines made with different bind group layouts"
+        );
+        assert_eq!(pipelines.simulation(), self.simulation());
+
+        self.pipelines = pipelines;
+    }
+
+    /// Rebuilds this system's pipelines from new shader source. If anything fails to compile
+    /// the error is returned and the pipelines we already had stay in place.
+    /// Systems sharing pipelines are better off with `Pipelines::reload` and `set_pipelines`.
+    pub fn reload_shaders(
+        &mut self,
+        device: &wgpu::Device,
+        sources: &Sources,
+    ) -> Result<(), ShaderError> {
+        self.pipelines = Arc::new(self.pipelines.reload(device, sources)?);
+        Ok(())
+    }
+
+    /// Rebuilds the render pipelines to draw into `format` targets with `sample_count` samples,
+    /// for when the host's targets change.
+    pub fn set_target_format(
+        &mut self,
+        device: &wgpu::Device,
+        format: TextureFormat,
+        sample_count: u32,
+    ) -> Result<(), ShaderError> {
+        if (format, sample_count) == (self.pipelines.format(), self.pipelines.sample_count()) {
+            return Ok(());
         }
+
+        self.pipelines = Arc::new(self.pipelines.retarget(device, format, sample_count)?);
+        Ok(())
+    }
+
+    /// Swaps in a different effect. Particles already in flight keep going as they were, but are drawn with
+    /// the new blend mode. If the effect's WGSL hooks don't compile, the error is returned and the previous
+    /// effect stays in place. The effect's `capacity` isn't applied, that's up to `resize`.
+    pub fn set_effect(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        effect: &Effect,
+    ) -> Result<(), ShaderError> {
+        self.pipelines = self.pipelines.for_effect(device, effect)?;
+        self.effect_params = effect.to_params();
+        self.render.blend = effect.blend;
+        queue.write_buffer(
+            &self.shared.effect,
+            0,
+            bytemuck::bytes_of(&self.effect_params),
+        );
+        Ok(())
+    }
+
+    /// Draws the particles with `texture` from now on.
+    pub fn set_texture(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        texture: &ParticleTexture,
+    ) {
+        self.render.texture_bind_group = Render::create_texture_bind_group(
+            device,
+            queue,
+            &self.pipelines.layouts.texture,
+            texture,
+        );
+    }
+
+    /// Grows or shrinks the particle pool in place, see `Compute::resize`, and rebuilds the particle lists to match.
+    /// The copy is queued behind any work already submitted, so there's no stall.
+    /// `capacity` should come from `particle_capacity`.
+    pub fn resize(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, capacity: u32) {
+        if capacity == self.capacity {
+            return;
+        }
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("particle buffer resize"),
+        });
+
+        let layouts = &self.pipelines.layouts;
+        self.compute
+            .resize(device, layouts, &mut encoder, self.capacity, capacity);
+        self.render.bind_group = Render::create_bind_group(device, &layouts.render, &self.compute);
+
+        self.capacity = capacity;
+        self.rebuild_lists(queue, &mut encoder);
+        queue.submit(Some(encoder.finish()));
+
+        if let Some(sim) = &mut self.cpu_sim {
+            sim.resize(capacity);
+            Self::upload_cpu_sim(queue, &self.compute, sim);
+        }
+    }
+
+    /// Replaces the whole pool with `particles`, followed by dead ones to fill it up,
+    /// and rebuilds the particle lists to match. Panics if there are more than fit.
+    #[cfg(test)]
+    pub(crate) fn upload_particles(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        particles: &[Particle],
+    ) {
+        let mut pool = vec![bytemuck::Zeroable::zeroed(); self.capacity as usize];
+        pool[..particles.len()].copy_from_slice(particles);
+        queue.write_buffer(
+            &self.compute.particle_buffer,
+            0,
+            bytemuck::cast_slice(&pool),
+        );
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("particle upload"),
+        });
+        self.rebuild_lists(queue, &mut encoder);
+        queue.submit(Some(encoder.finish()));
+    }
+
+    /// Records one simulation step with `params`: the physics pass, which also rebuilds the alive list,
+    /// then the emission pass. When simulating on the CPU, the step happens right here instead and
+    /// nothing is recorded.
+    ///
+    /// The params are written with `queue.write_buffer`, so every step recorded into one submission
+    /// sees the last params written, as the demo's fixed steps do.
+    pub fn update(
+        &mut self,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        params: &UpdateParams,
+    ) {
+        self.update_timed(queue, encoder, params, &mut GpuTimer::disabled());
+    }
+
+    /// `update`, with the passes bracketed by `timer`.
+    pub fn update_timed(
+        &mut self,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        params: &UpdateParams,
+        timer: &mut GpuTimer,
+    ) {
+        self.uniforms = Uniforms::from_params(params);
+        queue.write_buffer(&self.shared.uniforms, 0, bytemuck::bytes_of(&self.uniforms));
+
+        match (&self.pipelines.compute, &mut self.cpu_sim) {
+            (Some(_), _) => {}
+            (None, Some(sim)) => {
+                sim.step(&self.uniforms, &self.effect_params);
+                Self::upload_cpu_sim(queue, &self.compute, sim);
+                return;
+            }
+            (None, None) => unreachable!("no compute pipelines, but no CPU simulation either"),
+        }
+
+        let step = StageStep {
+            capacity: self.capacity,
+            effect: &self.effect_params,
+            dt: params.dt * params.time_scale,
+        };
+        for stage in &self.stages {
+            timer.begin(encoder, stage.timed_as());
+            {
+                let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                    label: Some(stage.label()),
+                });
+                cpass.set_bind_group(0, &self.compute.bind_group, &[]);
+                cpass.set_bind_group(1, &self.shared.compute_bind_group, &[]);
+                if let Some(bind_group) = stage.bind_group() {
+                    cpass.set_bind_group(2, bind_group, &[]);
+                }
+                stage.record(&mut cpass, &self.pipelines, &step);
+            }
+            timer.end(encoder);
+        }
+    }
+
+    /// Records a render pass drawing the live particles into `target`, see `ColorTarget`.
+    pub fn render(&self, encoder: &mut wgpu::CommandEncoder, target: &ColorTarget) {
+        let mut render_pass = target.begin_render_pass(encoder);
+        self.draw(&mut render_pass);
+    }
+
+    /// Draws the live particles into `render_pass`, which needs to match the system's target format and
+    /// sample count. For hosts with a pass of their own already going, otherwise see `render`. Sets its
+    /// own pipeline and bind groups 0 to 2, so rebind yours afterwards.
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        render_pass.set_pipeline(self.pipelines.render_pipeline(self.render.blend));
+        render_pass.set_bind_group(0, &self.render.bind_group, &[]);
+        render_pass.set_bind_group(1, &self.shared.render_bind_group, &[]);
+        render_pass.set_bind_group(2, &self.render.texture_bind_group, &[]);
+        // one instance per live particle, counted up by the compute passes
+        render_pass.draw_indirect(&self.compute.draw_args, 0);
+    }
+
+    /// Copies what the render pass needs out of `sim`: the particles, the alive list and its length.
+    /// Several steps in a frame are fine, the writes land in order before the frame's commands run.
+    fn upload_cpu_sim(queue: &wgpu::Queue, compute: &Compute, sim: &CpuSim) {
+        queue.write_buffer(
+            &compute.particle_buffer,
+            0,
+            bytemuck::cast_slice(sim.particles()),
+        );
+
+        let alive = sim.alive();
+        if !alive.is_empty() {
+            queue.write_buffer(&compute.alive_list, 0, bytemuck::cast_slice(alive));
+        }
+        queue.write_buffer(
+            &compute.draw_args,
+            0,
+            bytemuck::bytes_of(&DrawArgs {
+                instance_count: alive.len() as u32,
+                ..DrawArgs::EMPTY
+            }),
+        );
+    }
+
+    /// Empties both particle lists and refills them from whatever's in the particle buffer
+    /// once `encoder` runs.
+    fn rebuild_lists(&self, queue: &wgpu::Queue, encoder: &mut wgpu::CommandEncoder) {
+        // written before the encoder runs, so the rebuild starts from empty lists
+        queue.write_buffer(
+            &self.shared.helper_data,
+            0,
+            bytemuck::bytes_of(&HelperData {
+                max_particles: self.capacity,
+                dead_count: 0,
+            }),
+        );
+        queue.write_buffer(
+            &self.compute.draw_args,
+            0,
+            bytemuck::bytes_of(&DrawArgs::EMPTY),
+        );
+        self.compute.rebuild_lists(
+            encoder,
+            &self.pipelines,
+            &self.shared.compute_bind_group,
+            self.capacity,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effect;
+    use crate::preprocess::preprocess;
+    use crate::shaders::{COMPUTE_SHADER_FILE, RENDER_SHADER_FILE};
+
+    /// A struct's size and its (non-padding) fields' offsets, keyed by the name used in WGSL.
+    #[derive(Debug, PartialEq)]
+    struct Layout {
+        size: u32,
+        fields: Vec<(String, u32)>,
+    }
+
+    macro_rules! rust_layout {
+        ($ty:ty { $($wgsl:literal => $field:ident),* $(,)? }) => {
+            Layout {
+                size: std::mem::size_of::<$ty>() as u32,
+                fields: vec![$((
+                    $wgsl.to_string(),
+                    bytemuck::offset_of!(<$ty as bytemuck::Zeroable>::zeroed(), $ty, $field) as u32,
+                )),*],
+            }
+        };
+    }
+
+    /// How naga laid out `name` in the built-in `file`, `None` if the shader doesn't declare it.
+    fn wgsl_layout(file: &str, name: &str) -> Option<Layout> {
+        let preprocessed = preprocess(&Sources::built_in(), file, &shader_defines())
+            .unwrap_or_else(|e| panic!("{}", e));
+        let source = preprocessed.source.as_str();
+
+        let module = naga::front::wgsl::parse_str(source)
+            .unwrap_or_else(|e| panic!("{}:\n{}", file, e.emit_to_string(source)));
+
+        let layout = module.types.iter().find_map(|(_, ty)| match &ty.inner {
+            naga::TypeInner::Struct { members, span, .. } if ty.name.as_deref() == Some(name) => {
+                Some(Layout {
+                    size: *span,
+                    fields: members
+                        .iter()
+                        .map(|member| (member.name.clone().unwrap_or_default(), member.offset))
+                        .collect(),
+                })
+            }
+            _ => None,
+        });
+        layout
+    }
+
+    /// Checks every shader that declares `name` agrees with `rust`, and that at least one does.
+    fn check(name: &str, rust: Layout) {
+        let mut found = false;
+        for file in [COMPUTE_SHADER_FILE, RENDER_SHADER_FILE] {
+            if let Some(wgsl) = wgsl_layout(file, name) {
+                assert_eq!(
+                    wgsl, rust,
+                    "{} in {} doesn't match the Rust struct",
+                    name, file
+                );
+                found = true;
+            }
+        }
+
+        assert!(found, "no shader declares {}", name);
+    }
+
+    #[test]
+    fn helper_data_layout() {
+        check(
+            "HelperData",
+            rust_layout!(HelperData {
+                "maxParticles" => max_particles,
+                "deadCount" => dead_count,
+            }),
+        );
+    }
+
+    #[test]
+    fn uniforms_layout() {
+        check(
+            "Uniforms",
+            rust_layout!(Uniforms {
+                "paused" => paused,
+                "mouse_down" => mouse_down,
+                "mouse_pos_last" => mouse_pos_last,
+                "dt" => dt,
+                "time" => time,
+                "time_scale" => time_scale,
+            }),
+        );
+    }
+
+    #[test]
+    fn particle_layout() {
+        check(
+            "Particle",
+            rust_layout!(Particle {
+                "pos" => pos,
+                "vel" => vel,
+                "col" => col,
+                "lifetime" => lifetime,
+                "max_lifetime" => max_lifetime,
+                "emitter" => emitter,
+            }),
+        );
+    }
+
+    #[test]
+    fn effect_layout() {
+        check(
+            "Emitter",
+            rust_layout!(EmitterParams {
+                "offset" => offset,
+                "count" => count,
+                "shape" => shape,
+                "lifetime" => lifetime,
+                "speed" => speed,
+                "friction" => friction,
+            }),
+        );
+        check(
+            "Effect",
+            rust_layout!(EffectParams {
+                "emitter_count" => emitter_count,
+                "emitters" => emitters,
+            }),
+        );
+    }
+
+    /// What compiling `effect`'s WGSL into compute.wgsl comes back with, short of making the module.
+    fn check_effect(effect: &str) -> Result<(), ShaderError> {
+        let effect = Effect::parse(effect).unwrap_or_else(|e| panic!("{}", e));
+        let snippet = effect.wgsl.as_ref().expect("the effect has no wgsl");
+
+        let sources = with_snippet(&Sources::built_in(), snippet);
+        shaders::check(&sources, shaders::COMPUTE_SHADER_FILE, &shader_defines())
+            .map(drop)
+            .map_err(|e| snippet_error(snippet, e))
+    }
+
+    #[test]
+    fn effect_wgsl_compiles() {
+        let checked = check_effect(&format!("wgsl = '''\n{}'''\n[[emitter]]\n", effect::FORCE));
+        checked.unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    #[test]
+    fn effect_wgsl_parse_errors_point_at_the_effect() {
+        match check_effect(effect::BROKEN_EFFECT) {
+            Err(ShaderError::Parse {
+                at, diagnostics, ..
+            }) => {
+                assert_eq!(at, "effect line 5");
+                // numbered by the preprocessed compute.wgsl, so left out
+                assert_eq!(diagnostics, "");
+            }
+            other => panic!("wrong result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn effect_wgsl_validation_errors_point_at_the_effect() {
+        // `force` returns an f32
+        let checked = check_effect(concat!(
+            "wgsl = '''\n",
+            "fn helper() -> f32 {\n",
+            "    return 1.0;\n",
+            "}\n",
+            "\n",
+            "fn force(p: Particle, t: f32) -> vec3<f32> {\n",
+            "    return helper();\n",
+            "}\n",
+            "'''\n",
+            "[[emitter]]\n",
+        ));
+        match checked {
+            Err(ShaderError::Validation { at, message }) => {
+                assert_eq!(at, "effect line 6");
+                assert!(message.contains("'force'"), "{}", message);
+            }
+            other => panic!("wrong result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn emit_workgroups_stop_at_capacity() {
+        let effect = Effect::default().to_params();
+        assert_eq!(
+            Compute::emit_workgroups(&effect, 0.0, 1 << 16),
+            (1, effect.emitter_count)
+        );
+
+        // a step long enough to emit billions of particles still fits in one dimension's dispatch limit
+        let (x, _) = Compute::emit_workgroups(&effect, 1e6, 1 << 16);
+        assert_eq!(x, (1 << 16) / EMIT_WORKGROUP_SIZE);
+    }
+
+    #[test]
+    fn draw_args_layout() {
+        check(
+            "DrawArgs",
+            rust_layout!(DrawArgs {
+                "vertex_count" => vertex_count,
+                "instance_count" => instance_count,
+                "base_vertex" => base_vertex,
+                "base_instance" => base_instance,
+            }),
+        );
     }
 }
@@ -0,0 +1,358 @@
+//! A very small preprocessor for the WGSL, so the shaders can share declarations.
+//!
+//! Directives take up a whole line (give or take a `//` comment) and start with `#`:
+//! - `#include "common.wgsl"` pastes in another shader file. Each file is only ever pasted in once,
+//!   later includes of it are skipped, so including the same thing from two places is fine.
+//! - `#define NAME value` replaces every later `NAME` (as a whole identifier, outside comments) with `value`.
+//!   Redefining a name replaces it from there on. The value isn't itself expanded.
+//!
+//! Defines can also be passed in from Rust, which is how the shaders pick up sizes that need to agree with
+//! pipelines.rs. WGSL doesn't use `#` for anything, so there's no ambiguity.
+
+use crate::shaders::Sources;
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// Where a line of preprocessed output came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    pub file: String,
+    /// 1-based, like naga's and editors'.
+    pub line: usize,
+}
+
+impl fmt::Display for SourceLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// A shader with its includes pasted in and its defines expanded.
+#[derive(Debug)]
+pub struct Preprocessed {
+    pub source: String,
+    /// `lines[i]` is where line `i + 1` of `source` came from.
+    lines: Vec<SourceLine>,
+}
+
+impl Preprocessed {
+    /// Where 1-based `line` of the output came from, for pointing errors at the file that needs fixing.
+    pub fn origin(&self, line: usize) -> Option<&SourceLine> {
+        line.checked_sub(1).and_then(|i| self.lines.get(i))
+    }
+}
+
+#[derive(Debug)]
+pub enum PreprocessError {
+    /// The file we were asked to preprocess doesn't exist.
+    UnknownFile { file: String },
+    /// An `#include` named a file we don't have.
+    MissingInclude { at: SourceLine, file: String },
+    /// A line started with `#` but wasn't a directive we understand.
+    BadDirective { at: SourceLine, directive: String },
+}
+
+impl fmt::Display for PreprocessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PreprocessError::UnknownFile { file } => write!(f, "no shader called {:?}", file),
+            PreprocessError::MissingInclude { at, file } => {
+                write!(f, "{}: can't include {:?}, no such shader", at, file)
+            }
+            PreprocessError::BadDirective { at, directive } => {
+                write!(f, "{}: don't know what to do with {:?}", at, directive)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PreprocessError {}
+
+/// Preprocesses `file` from `sources`, starting out with `defines` already defined.
+pub fn preprocess(
+    sources: &Sources,
+    file: &str,
+    defines: &[(&str, String)],
+) -> Result<Preprocessed, PreprocessError> {
+    let mut preprocessor = Preprocessor {
+        sources,
+        defines: defines
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect(),
+        included: Vec::new(),
+        output: Preprocessed {
+            source: String::new(),
+            lines: Vec::new(),
+        },
+    };
+
+    let source = sources
+        .get(file)
+        .ok_or_else(|| PreprocessError::UnknownFile {
+            file: file.to_string(),
+        })?;
+    preprocessor.included.push(file.to_string());
+    preprocessor.file(file, source)?;
+
+    Ok(preprocessor.output)
+}
+
+struct Preprocessor<'a> {
+    sources: &'a Sources,
+    defines: HashMap<String, String>,
+    /// Files already pasted in, which get skipped from then on.
+    included: Vec<String>,
+    output: Preprocessed,
+}
+
+impl<'a> Preprocessor<'a> {
+    fn file(&mut self, file: &str, source: &str) -> Result<(), PreprocessError> {
+        for (i, line) in source.lines().enumerate() {
+            let at = SourceLine {
+                file: file.to_string(),
+                line: i + 1,
+            };
+
+            let directive = match line.trim_start().strip_prefix('#') {
+                Some(directive) => match directive.find("//") {
+                    Some(comment) => directive[..comment].trim(),
+                    None => directive.trim(),
+                },
+                None => {
+                    let expanded = self.expand(line);
+                    self.push(&expanded, at);
+                    continue;
+                }
+            };
+
+            let (keyword, rest) = directive
+                .split_once(char::is_whitespace)
+                .map_or((directive, ""), |(keyword, rest)| (keyword, rest.trim()));
+
+            match keyword {
+                "include" => {
+                    let include = match rest.strip_prefix('"').and_then(|r| r.strip_suffix('"')) {
+                        Some(include) => include,
+                        None => {
+                            return Err(PreprocessError::BadDirective {
+                                at,
+                                directive: line.trim().to_string(),
+                            })
+                        }
+                    };
+
+                    if !self.included.iter().any(|done| done == include) {
+                        let sources = self.sources;
+                        let source = sources.get(include).ok_or_else(|| {
+                            PreprocessError::MissingInclude {
+                                at: at.clone(),
+                                file: include.to_string(),
+                            }
+                        })?;
+
+                        self.included.push(include.to_string());
+                        self.file(include, source)?;
+                    }
+                }
+                "define" if is_identifier(rest.split_whitespace().next().unwrap_or("")) => {
+                    let (name, value) = rest
+                        .split_once(char::is_whitespace)
+                        .map_or((rest, ""), |(name, value)| (name, value.trim()));
+
+                    self.defines.insert(name.to_string(), value.to_string());
+                }
+                _ => {
+                    return Err(PreprocessError::BadDirective {
+                        at,
+                        directive: line.trim().to_string(),
+                    })
+                }
+            }
+
+            // keep the line count (and so naga's line numbers) close to the original's
+            self.push("", at);
+        }
+
+        Ok(())
+    }
+
+    fn push(&mut self, line: &str, at: SourceLine) {
+        self.output.source.push_str(line);
+        self.output.source.push('\n');
+        self.output.lines.push(at);
+    }
+
+    /// Replaces the defined identifiers in `line`, leaving any `//` comment alone.
+    fn expand(&self, line: &str) -> String {
+        if self.defines.is_empty() {
+            return line.to_string();
+        }
+
+        let (code, comment) = match line.find("//") {
+            Some(start) => line.split_at(start),
+            None => (line, ""),
+        };
+
+        let mut expanded = String::with_capacity(line.len());
+        let mut rest = code;
+
+        while let Some(start) = rest.find(is_identifier_start) {
+            let (before, from) = rest.split_at(start);
+            let end = from
+                .find(|c: char| !is_identifier_char(c))
+                .unwrap_or(from.len());
+            let (word, after) = from.split_at(end);
+
+            expanded.push_str(before);
+
+            // digits followed by letters are a literal with a suffix (`1u`), not an identifier
+            let in_number = before.ends_with(|c: char| c.is_ascii_digit() || c == '.');
+            match self.defines.get(word) {
+                Some(value) if !in_number => expanded.push_str(value),
+                _ => expanded.push_str(word),
+            }
+
+            rest = after;
+        }
+
+        expanded.push_str(rest);
+        expanded.push_str(comment);
+        expanded
+    }
+}
+
+fn is_identifier_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn is_identifier(word: &str) -> bool {
+    word.starts_with(is_identifier_start) && word.chars().all(is_identifier_char)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The built-in shaders plus `files`, which are named so as not to clash with them.
+    fn sources(files: &[(&'static str, &'static str)]) -> Sources {
+        let mut sources = Sources::built_in();
+        for &(file, source) in files {
+            sources.insert(file, source);
+        }
+        sources
+    }
+
+    fn source_of(sources: &Sources, file: &str, defines: &[(&str, String)]) -> String {
+        preprocess(sources, file, defines)
+            .unwrap_or_else(|e| panic!("{}", e))
+            .source
+    }
+
+    #[test]
+    fn includes_are_pasted_once() {
+        let sources = sources(&[
+            ("a.wgsl", "let a = 1;"),
+            ("b.wgsl", "#include \"a.wgsl\"\nlet b = 2;"),
+            (
+                "main.wgsl",
+                "#include \"a.wgsl\"\n#include \"b.wgsl\" // a is in here too\nlet c = 3;",
+            ),
+        ]);
+
+        let source = source_of(&sources, "main.wgsl", &[]);
+        assert_eq!(source.matches("let a").count(), 1);
+        assert!(source.contains("let b = 2;"));
+        assert!(source.contains("let c = 3;"));
+    }
+
+    #[test]
+    fn missing_include_says_where() {
+        let sources = sources(&[("main.wgsl", "let a = 1;\n#include \"nope.wgsl\"")]);
+
+        match preprocess(&sources, "main.wgsl", &[]) {
+            Err(PreprocessError::MissingInclude { at, file }) => {
+                assert_eq!(at.to_string(), "main.wgsl:2");
+                assert_eq!(file, "nope.wgsl");
+            }
+            other => panic!("wrong result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bad_directive_says_where() {
+        let sources = sources(&[
+            ("inner.wgsl", "\n\n#ifdef FOO"),
+            ("main.wgsl", "#include \"inner.wgsl\""),
+        ]);
+
+        match preprocess(&sources, "main.wgsl", &[]) {
+            Err(PreprocessError::BadDirective { at, directive }) => {
+                assert_eq!(at.to_string(), "inner.wgsl:3");
+                assert_eq!(directive, "#ifdef FOO");
+            }
+            other => panic!("wrong result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn defines_replace_whole_identifiers() {
+        let sources = sources(&[(
+            "main.wgsl",
+            "#define SIZE 64u\nlet a = SIZE;\nlet b = SIZE_X + MY_SIZE;\nlet c = array<f32, COUNT>();",
+        )]);
+
+        let source = source_of(&sources, "main.wgsl", &[("COUNT", "4u".to_string())]);
+        assert!(source.contains("let a = 64u;"));
+        assert!(source.contains("let b = SIZE_X + MY_SIZE;"));
+        assert!(source.contains("let c = array<f32, 4u>();"));
+    }
+
+    #[test]
+    fn number_suffixes_arent_expanded() {
+        let sources = sources(&[(
+            "main.wgsl",
+            "#define u oops\n#define f oops\nlet a = 1u + 2.0f;",
+        )]);
+
+        let source = source_of(&sources, "main.wgsl", &[]);
+        assert!(source.contains("let a = 1u + 2.0f;"));
+    }
+
+    #[test]
+    fn comments_arent_expanded() {
+        let sources = sources(&[(
+            "main.wgsl",
+            "#define SIZE 64u\nlet a = SIZE; // SIZE particles",
+        )]);
+
+        let source = source_of(&sources, "main.wgsl", &[]);
+        assert!(source.contains("let a = 64u; // SIZE particles"));
+    }
+
+    #[test]
+    fn lines_map_back_to_their_files() {
+        let sources = sources(&[
+            ("inner.wgsl", "let a = 1;\nlet b = 2;"),
+            ("main.wgsl", "// main\n#include \"inner.wgsl\"\nlet c = 3;"),
+        ]);
+
+        let preprocessed =
+            preprocess(&sources, "main.wgsl", &[]).unwrap_or_else(|e| panic!("{}", e));
+        let origin = |line| preprocessed.origin(line).map(ToString::to_string);
+
+        assert_eq!(origin(1).as_deref(), Some("main.wgsl:1"));
+        assert_eq!(origin(2).as_deref(), Some("inner.wgsl:1"));
+        assert_eq!(origin(3).as_deref(), Some("inner.wgsl:2"));
+        // the `#include` line itself is kept as a blank one after what it pasted in
+        assert_eq!(origin(4).as_deref(), Some("main.wgsl:2"));
+        assert_eq!(origin(5).as_deref(), Some("main.wgsl:3"));
+        assert_eq!(origin(0), None);
+        assert_eq!(origin(6), None);
+    }
+}
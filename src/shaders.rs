@@ -0,0 +1,238 @@
+use crate::preprocess::{self, PreprocessError, Preprocessed};
+use wgpu::{ShaderModule, ShaderModuleDescriptor, ShaderSource};
+
+use std::borrow::Cow;
+use std::fmt;
+
+/// The shaders as they were at build time.
+pub const COMMON_SHADER: &str = include_str!("common.wgsl");
+pub const COMPUTE_COMMON_SHADER: &str = include_str!("compute_common.wgsl");
+pub const COMPUTE_SHADER: &str = include_str!("compute.wgsl");
+pub const BEHAVIOUR_SHADER: &str = include_str!("behaviour.wgsl");
+pub const RENDER_SHADER: &str = include_str!("frag_vert.wgsl");
+
+/// Where the shaders live in the source tree, for reloading them at runtime.
+#[cfg(not(target_arch = "wasm32"))]
+pub const SHADER_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src");
+pub const COMMON_SHADER_FILE: &str = "common.wgsl";
+pub const COMPUTE_COMMON_SHADER_FILE: &str = "compute_common.wgsl";
+pub const COMPUTE_SHADER_FILE: &str = "compute.wgsl";
+pub const BEHAVIOUR_SHADER_FILE: &str = "behaviour.wgsl";
+pub const RENDER_SHADER_FILE: &str = "frag_vert.wgsl";
+
+/// Every shader file, the ones only ever `#include`d too.
+pub const SHADER_FILES: [&str; 5] = [
+    COMMON_SHADER_FILE,
+    COMPUTE_COMMON_SHADER_FILE,
+    COMPUTE_SHADER_FILE,
+    BEHAVIOUR_SHADER_FILE,
+    RENDER_SHADER_FILE,
+];
+
+/// The text of every file in `SHADER_FILES`, for the preprocessor to pick includes from.
+#[derive(Clone)]
+pub struct Sources {
+    files: Vec<(Cow<'static, str>, Cow<'static, str>)>,
+}
+
+impl Sources {
+    pub fn built_in() -> Self {
+        Sources {
+            files: vec![
+                (COMMON_SHADER_FILE.into(), COMMON_SHADER.into()),
+                (
+                    COMPUTE_COMMON_SHADER_FILE.into(),
+                    COMPUTE_COMMON_SHADER.into(),
+                ),
+                (COMPUTE_SHADER_FILE.into(), COMPUTE_SHADER.into()),
+                (BEHAVIOUR_SHADER_FILE.into(), BEHAVIOUR_SHADER.into()),
+                (RENDER_SHADER_FILE.into(), RENDER_SHADER.into()),
+            ],
+        }
+    }
+
+    /// Reads the shaders fresh from the source tree.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn read_from_disk() -> std::io::Result<Self> {
+        let dir = std::path::Path::new(SHADER_DIR);
+
+        let files = SHADER_FILES
+            .iter()
+            .map(|&file| Ok((file.into(), std::fs::read_to_string(dir.join(file))?.into())))
+            .collect::<std::io::Result<_>>()?;
+
+        Ok(Sources { files })
+    }
+
+    /// Adds `file`, or replaces it if there's one by that name already. For shaders of your own that `#include`
+    /// the built-in ones, see stages.rs.
+    pub fn insert(
+        &mut self,
+        file: impl Into<Cow<'static, str>>,
+        source: impl Into<Cow<'static, str>>,
+    ) {
+        let (file, source) = (file.into(), source.into());
+        match self.files.iter_mut().find(|(name, _)| *name == file) {
+            Some((_, existing)) => *existing = source,
+            None => self.files.push((file, source)),
+        }
+    }
+
+    pub fn get(&self, file: &str) -> Option<&str> {
+        self.files
+            .iter()
+            .find(|(name, _)| name == file)
+            .map(|(_, source)| source.as_ref())
+    }
+}
+
+#[derive(Debug)]
+pub enum ShaderError {
+    /// An `#include` or `#define` was wrong, see preprocess.rs.
+    Preprocess(PreprocessError),
+    /// The WGSL didn't parse. `at` is the `file:line` it went wrong on and `diagnostics` is naga's
+    /// pretty-printed report, whose line numbers are in the preprocessed source. Empty when they'd only
+    /// confuse, see `Pipelines::for_effect`.
+    Parse {
+        at: String,
+        message: String,
+        diagnostics: String,
+    },
+    /// The WGSL parsed but naga's validator rejected it. `at` is the `file:line` of the function it
+    /// objected to, or just the file if it wasn't about one.
+    Validation { at: String, message: String },
+    /// The shader was fine on its own but wgpu wouldn't build a pipeline from it,
+    /// usually because it disagrees with the bind group layouts.
+    Pipeline { message: String },
+}
+
+impl fmt::Display for ShaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShaderError::Preprocess(e) => write!(f, "{}", e),
+            ShaderError::Parse {
+                at,
+                message,
+                diagnostics,
+            } => match diagnostics.as_str() {
+                "" => write!(f, "{}: failed to parse: {}", at, message),
+                _ => write!(f, "{}: failed to parse:\n{}", at, diagnostics),
+            },
+            ShaderError::Validation { at, message } => {
+                write!(f, "{}: failed validation: {}", at, message)
+            }
+            ShaderError::Pipeline { message } => {
+                write!(f, "couldn't create pipeline: {}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ShaderError {}
+
+/// Preprocesses `file` and checks it with naga before handing it to wgpu, so mistakes come back as errors
+/// (with line numbers) rather than wgpu's uncaptured error panic.
+pub fn compile(
+    device: &wgpu::Device,
+    sources: &Sources,
+    file: &str,
+    defines: &[(&str, String)],
+) -> Result<ShaderModule, ShaderError> {
+    let preprocessed = check(sources, file, defines)?;
+
+    Ok(device.create_shader_module(&ShaderModuleDescriptor {
+        label: Some(file),
+        source: ShaderSource::Wgsl(preprocessed.source.into()),
+    }))
+}
+
+/// `compile` without the device: preprocesses `file` and has naga parse and validate it.
+pub fn check(
+    sources: &Sources,
+    file: &str,
+    defines: &[(&str, String)],
+) -> Result<Preprocessed, ShaderError> {
+    let preprocessed =
+        preprocess::preprocess(sources, file, defines).map_err(ShaderError::Preprocess)?;
+    let source = preprocessed.source.as_str();
+    let at = |line| match preprocessed.origin(line) {
+        Some(origin) => origin.to_string(),
+        None => file.to_string(),
+    };
+
+    let module = naga::front::wgsl::parse_str(source).map_err(|e| ShaderError::Parse {
+        at: at(e.location(source).0),
+        message: e.to_string(),
+        diagnostics: e.emit_to_string(source),
+    })?;
+
+    naga::valid::Validator::new(
+        naga::valid::ValidationFlags::all(),
+        naga::valid::Capabilities::empty(),
+    )
+    .validate(&module)
+    .map_err(|e| {
+        // naga doesn't say where, but it does name the function, which is usually enough to find it
+        let function = match &e {
+            naga::valid::ValidationError::Function { name, .. }
+            | naga::valid::ValidationError::EntryPoint { name, .. } => fn_line(source, name),
+            _ => None,
+        };
+
+        // the top level only names what's invalid, the why is further down
+        let mut message = e.to_string();
+        let mut cause = std::error::Error::source(&e);
+        while let Some(e) = cause {
+            message.push_str(&format!(": {}", e));
+            cause = e.source();
+        }
+
+        ShaderError::Validation {
+            at: function.map_or_else(|| file.to_string(), at),
+            message,
+        }
+    })?;
+
+    Ok(preprocessed)
+}
+
+/// The 1-based line `fn name` is declared on in `source`, ignoring comments.
+fn fn_line(source: &str, name: &str) -> Option<usize> {
+    source
+        .lines()
+        .position(|line| {
+            let code = line.split("//").next().unwrap_or("");
+            let mut words = code
+                .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .filter(|word| !word.is_empty());
+            words.any(|word| word == "fn") && words.next() == Some(name)
+        })
+        .map(|i| i + 1)
+}
+
+/// Runs `create` with wgpu validation errors captured instead of panicking.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn catch_pipeline_errors<T>(
+    device: &wgpu::Device,
+    create: impl FnOnce() -> T,
+) -> Result<T, ShaderError> {
+    device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let created = create();
+
+    // native error scopes resolve immediately, so this doesn't actually block
+    match futures::executor::block_on(device.pop_error_scope()) {
+        None => Ok(created),
+        Some(e) => Err(ShaderError::Pipeline {
+            message: e.to_string(),
+        }),
+    }
+}
+
+/// On the web the error scope only resolves on a later tick, so errors just go to the console.
+#[cfg(target_arch = "wasm32")]
+pub fn catch_pipeline_errors<T>(
+    _device: &wgpu::Device,
+    create: impl FnOnce() -> T,
+) -> Result<T, ShaderError> {
+    Ok(create())
+}
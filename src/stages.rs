@@ -0,0 +1,223 @@
+//! The compute passes a `ParticleSystem` runs every step, in order. The built-in ones are `Physics` and
+//! `Emission`; anything else (extra forces, colour rules, collision...) can be added as a `ParticleStage`
+//! of its own, usually a `WgslStage`, without touching the system itself.
+//!
+//! Every stage's pipeline sees the system's buffers the way compute.wgsl does: bind groups 0 and 1, declared
+//! in compute_common.wgsl for stage shaders to `#include`. A stage can bring a bind group 2 of its own.
+
+use crate::gpu_timer::Pass;
+use crate::pipelines::{shader_defines, Compute, ComputePipelines, EffectParams, Pipelines};
+use crate::shaders::{self, ShaderError};
+use std::borrow::Cow;
+use wgpu::{BindGroup, BindGroupLayout, ComputePass, ComputePipeline, ComputePipelineDescriptor};
+
+/// What a stage gets to size its dispatch by.
+#[derive(Debug, Clone, Copy)]
+pub struct StageStep<'a> {
+    /// Of the system, live or dead.
+    pub capacity: u32,
+    pub effect: &'a EffectParams,
+    /// Seconds simulated by this step, already scaled by the time scale.
+    pub dt: f32,
+}
+
+/// One compute pass over a system's particles, recorded once per step.
+pub trait ParticleStage {
+    /// For the compute pass and wgpu's error messages.
+    fn label(&self) -> &str;
+
+    /// Layout of the stage's own bind group 2, if it has one. Its pipeline has to be made with it,
+    /// see `WgslStage::new`.
+    fn bind_group_layout(&self) -> Option<&BindGroupLayout> {
+        None
+    }
+
+    /// Bound at group 2, made with `bind_group_layout`.
+    fn bind_group(&self) -> Option<&BindGroup> {
+        None
+    }
+
+    /// What to run. `pipelines` are the system's, for stages that are built from its shaders.
+    fn pipeline<'a>(&'a self, pipelines: &'a Pipelines) -> &'a ComputePipeline;
+
+    /// Workgroups to dispatch, in x, y and z.
+    fn workgroups(&self, step: &StageStep) -> (u32, u32, u32);
+
+    /// Records the stage into its pass, which has bind groups 0 and 1 (and `bind_group` at 2) set already.
+    /// Runs `pipeline` over `workgroups` unless a stage has more to do.
+    fn record<'a>(
+        &'a self,
+        cpass: &mut ComputePass<'a>,
+        pipelines: &'a Pipelines,
+        step: &StageStep,
+    ) {
+        cpass.set_pipeline(self.pipeline(pipelines));
+        let (x, y, z) = self.workgroups(step);
+        cpass.dispatch(x, y, z);
+    }
+
+    /// Which of the GPU timings the pass counts towards.
+    fn timed_as(&self) -> Pass {
+        Pass::Physics
+    }
+}
+
+/// Ages and moves the particles, sorting them onto the dead and alive lists. `reset_alive_list` and then
+/// `step_particles` in compute.wgsl, in the one pass.
+pub struct Physics;
+
+impl ParticleStage for Physics {
+    fn label(&self) -> &str {
+        "physics"
+    }
+
+    fn pipeline<'a>(&'a self, pipelines: &'a Pipelines) -> &'a ComputePipeline {
+        &compute_pipelines(pipelines).step
+    }
+
+    fn workgroups(&self, step: &StageStep) -> (u32, u32, u32) {
+        (Compute::step_workgroups(step.capacity), 1, 1)
+    }
+
+    fn record<'a>(
+        &'a self,
+        cpass: &mut ComputePass<'a>,
+        pipelines: &'a Pipelines,
+        step: &StageStep,
+    ) {
+        // the alive list is rebuilt from scratch as the particles are stepped
+        cpass.set_pipeline(&compute_pipelines(pipelines).reset_alive_list);
+        cpass.dispatch(1, 1, 1);
+
+        cpass.set_pipeline(self.pipeline(pipelines));
+        let (x, y, z) = self.workgroups(step);
+        cpass.dispatch(x, y, z);
+    }
+}
+
+/// Spawns the effect's particles at the mouse. `emit` in compute.wgsl.
+pub struct Emission;
+
+impl ParticleStage for Emission {
+    fn label(&self) -> &str {
+        "emission"
+    }
+
+    fn pipeline<'a>(&'a self, pipelines: &'a Pipelines) -> &'a ComputePipeline {
+        &compute_pipelines(pipelines).emit
+    }
+
+    fn workgroups(&self, step: &StageStep) -> (u32, u32, u32) {
+        let (x, y) = Compute::emit_workgroups(step.effect, step.dt, step.capacity);
+        (x, y, 1)
+    }
+
+    fn timed_as(&self) -> Pass {
+        Pass::Emission
+    }
+}
+
+/// What a system runs unless told otherwise.
+pub fn default_stages() -> Vec<Box<dyn ParticleStage>> {
+    vec![Box::new(Physics), Box::new(Emission)]
+}
+
+fn compute_pipelines(pipelines: &Pipelines) -> &ComputePipelines {
+    pipelines
+        .compute
+        .as_ref()
+        .expect("stages only run with the GPU simulation")
+}
+
+/// A stage from a WGSL entry point of your own. The shader can `#include "compute_common.wgsl"` for the particles,
+/// lists and uniforms, and gets the same defines as the built-in shaders (`PARTICLE_GROUP_SIZE` and so on).
+///
+/// A stage is compiled once, against the shaders of the pipelines it was made with. Reloading those
+/// (`ParticleSystem::reload_shaders`, or the demo's `--hot-reload`) doesn't rebuild it, so it keeps the
+/// `#include`d code it started with: make it again with `new` to pick up the changes.
+pub struct WgslStage {
+    entry_point: Cow<'static, str>,
+    pipeline: ComputePipeline,
+    bind_group: Option<(BindGroupLayout, BindGroup)>,
+    workgroups: Workgroups,
+}
+
+/// Sizes a `WgslStage`'s dispatches, see `WgslStage::new`.
+type Workgroups = Box<dyn Fn(&StageStep) -> (u32, u32, u32)>;
+
+impl WgslStage {
+    /// Compiles `source` as `file` against `pipelines`' shaders and builds a pipeline running `entry_point`.
+    /// `bind_group` is the stage's own group 2 and its layout, if it needs one. `workgroups` sizes each
+    /// dispatch, e.g. `WgslStage::per_particle_group`.
+    /// The pipeline works with any system whose pipelines were made from the same ones, reloaded or not.
+    pub fn new(
+        device: &wgpu::Device,
+        pipelines: &Pipelines,
+        file: impl Into<Cow<'static, str>>,
+        source: &str,
+        entry_point: impl Into<Cow<'static, str>>,
+        bind_group: Option<(BindGroupLayout, BindGroup)>,
+        workgroups: impl Fn(&StageStep) -> (u32, u32, u32) + 'static,
+    ) -> Result<Self, ShaderError> {
+        let (file, entry_point) = (file.into(), entry_point.into());
+
+        let mut sources = pipelines.sources().clone();
+        sources.insert(file.clone(), source.to_string());
+        let module = shaders::compile(device, &sources, &file, &shader_defines())?;
+
+        let layouts = &pipelines.layouts;
+        let mut bind_group_layouts = vec![&layouts.compute, &layouts.shared_compute];
+        if let Some((layout, _)) = &bind_group {
+            bind_group_layouts.push(layout);
+        }
+
+        let pipeline = shaders::catch_pipeline_errors(device, || {
+            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some(&file),
+                bind_group_layouts: &bind_group_layouts,
+                push_constant_ranges: &[],
+            });
+            device.create_compute_pipeline(&ComputePipelineDescriptor {
+                label: Some(&entry_point),
+                layout: Some(&layout),
+                module: &module,
+                entry_point: &entry_point,
+            })
+        })?;
+
+        Ok(WgslStage {
+            entry_point,
+            pipeline,
+            bind_group,
+            workgroups: Box::new(workgroups),
+        })
+    }
+
+    /// For `new`: one invocation per group of `PARTICLE_GROUP_SIZE` particles, in workgroups of
+    /// `STEP_WORKGROUP_SIZE`, the same as `step_particles`.
+    pub fn per_particle_group(step: &StageStep) -> (u32, u32, u32) {
+        (Compute::step_workgroups(step.capacity), 1, 1)
+    }
+}
+
+impl ParticleStage for WgslStage {
+    fn label(&self) -> &str {
+        &self.entry_point
+    }
+
+    fn bind_group_layout(&self) -> Option<&BindGroupLayout> {
+        self.bind_group.as_ref().map(|(layout, _)| layout)
+    }
+
+    fn bind_group(&self) -> Option<&BindGroup> {
+        self.bind_group.as_ref().map(|(_, bind_group)| bind_group)
+    }
+
+    fn pipeline<'a>(&'a self, _pipelines: &'a Pipelines) -> &'a ComputePipeline {
+        &self.pipeline
+    }
+
+    fn workgroups(&self, step: &StageStep) -> (u32, u32, u32) {
+        (self.workgroups)(step)
+    }
+}
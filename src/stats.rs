@@ -0,0 +1,248 @@
+use crate::timing::Stopwatch;
+use futures::FutureExt;
+use particles_demo::gpu_timer::{GpuTimer, Pass, PassTimings, MAX_TIMESTAMPS_SIZE, TIMESTAMP_SIZE};
+use particles_demo::pipelines::DrawArgs;
+use wgpu::{Buffer, BufferAsyncError, BufferDescriptor, BufferUsages, MapMode};
+
+use std::convert::TryInto;
+use std::future::Future;
+use std::pin::Pin;
+
+/// Readbacks in flight at once. Mapping takes a frame or two, so with a few of these we can start
+/// one every frame without waiting on the last.
+const READBACK_SLOTS: usize = 3;
+
+/// How often (in seconds) the frame rate is worked out and the stats are shown.
+const REPORT_INTERVAL: f64 = 0.5;
+
+/// Each staging buffer holds every system's draw args, followed by the frame's timestamps.
+const DRAW_ARGS_SIZE: u64 = std::mem::size_of::<DrawArgs>() as u64;
+
+/// What the GPU last told us. Always a couple of frames behind.
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen::prelude::wasm_bindgen)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Stats {
+    /// Particles alive after the last simulation step, over all systems.
+    pub alive: u32,
+    pub capacity: u32,
+    /// Frames per second, averaged since the last report.
+    pub fps: f32,
+    /// Whether the device can do timestamp queries. If not, the `*_ms` fields stay at 0.
+    pub gpu_timing: bool,
+    /// GPU milliseconds spent in each kind of pass, summed over all of a frame's steps.
+    pub physics_ms: f32,
+    pub emission_ms: f32,
+    pub render_ms: f32,
+}
+
+#[cfg(target_arch = "wasm32")]
+thread_local! {
+    static LATEST: std::cell::Cell<Stats> = std::cell::Cell::new(Stats::default());
+}
+
+/// The latest stats, for the page to show however it likes.
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen::prelude::wasm_bindgen(js_name = particleStats)]
+pub fn particle_stats() -> Stats {
+    LATEST.with(|latest| latest.get())
+}
+
+type MapFuture = Pin<Box<dyn Future<Output = Result<(), BufferAsyncError>>>>;
+
+enum SlotState {
+    Free,
+    /// A copy has been recorded but not submitted yet.
+    Copied {
+        frame: u64,
+        passes: Vec<Pass>,
+    },
+    Mapping {
+        frame: u64,
+        passes: Vec<Pass>,
+        mapped: MapFuture,
+    },
+}
+
+struct Slot {
+    buffer: Buffer,
+    state: SlotState,
+}
+
+/// Copies the draw args (whose instance count is the number of live particles) and pass timestamps
+/// into a ring of staging buffers, and picks the results up whenever they're ready. Never waits on the GPU.
+pub struct StatsCollector {
+    pub stats: Stats,
+    /// How many systems' draw args each readback holds.
+    systems: usize,
+    pub timer: GpuTimer,
+    /// Nanoseconds per timestamp tick.
+    timestamp_period: f32,
+    slots: Vec<Slot>,
+    frame: u64,
+    /// Frame the current `stats.alive` came from, so a late readback can't overwrite a newer one.
+    latest_frame: u64,
+    stopwatch: Stopwatch,
+    frames_since_report: u32,
+    last_report: f64,
+}
+
+impl StatsCollector {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, systems: usize) -> Self {
+        let timer = GpuTimer::new(device);
+
+        let slots = (0..READBACK_SLOTS)
+            .map(|_| Slot {
+                buffer: device.create_buffer(&BufferDescriptor {
+                    label: Some("stats readback"),
+                    size: systems as u64 * DRAW_ARGS_SIZE + MAX_TIMESTAMPS_SIZE,
+                    usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                }),
+                state: SlotState::Free,
+            })
+            .collect();
+
+        StatsCollector {
+            stats: Stats {
+                gpu_timing: timer.enabled(),
+                ..Stats::default()
+            },
+            systems,
+            timer,
+            timestamp_period: queue.get_timestamp_period(),
+            slots,
+            frame: 0,
+            latest_frame: 0,
+            stopwatch: Stopwatch::new(),
+            frames_since_report: 0,
+            last_report: 0.0,
+        }
+    }
+
+    /// Copies each system's `draw_args` and the frame's timestamps into a free staging buffer.
+    /// If they're all still busy this frame is skipped. Call at the end of the frame's encoder.
+    pub fn record(&mut self, encoder: &mut wgpu::CommandEncoder, draw_args: &[&Buffer]) {
+        assert_eq!(draw_args.len(), self.systems);
+        self.frame += 1;
+
+        // resolved even if we end up skipping the frame, so the timer starts afresh
+        let timestamps = self.timer.resolve(encoder);
+
+        let frame = self.frame;
+        if let Some(slot) = self
+            .slots
+            .iter_mut()
+            .find(|slot| matches!(slot.state, SlotState::Free))
+        {
+            for (i, buffer) in draw_args.iter().enumerate() {
+                let offset = i as u64 * DRAW_ARGS_SIZE;
+                encoder.copy_buffer_to_buffer(buffer, 0, &slot.buffer, offset, DRAW_ARGS_SIZE);
+            }
+            let timestamps_offset = draw_args.len() as u64 * DRAW_ARGS_SIZE;
+
+            let passes = match timestamps {
+                Some((resolved, passes)) => {
+                    encoder.copy_buffer_to_buffer(
+                        resolved,
+                        0,
+                        &slot.buffer,
+                        timestamps_offset,
+                        passes.len() as u64 * 2 * TIMESTAMP_SIZE,
+                    );
+                    passes
+                }
+                None => Vec::new(),
+            };
+
+            slot.state = SlotState::Copied { frame, passes };
+        }
+    }
+
+    /// Frame the current stats were read back from, it changes whenever a new readback lands.
+    pub fn latest_frame(&self) -> u64 {
+        self.latest_frame
+    }
+
+    /// Starts mapping whatever `record` copied. Call once the encoder has been submitted.
+    pub fn submitted(&mut self) {
+        for slot in &mut self.slots {
+            slot.state = match std::mem::replace(&mut slot.state, SlotState::Free) {
+                SlotState::Copied { frame, passes } => SlotState::Mapping {
+                    frame,
+                    passes,
+                    mapped: Box::pin(slot.buffer.slice(..).map_async(MapMode::Read)),
+                },
+                state => state,
+            };
+        }
+    }
+
+    /// Collects finished readbacks and counts the frame. Returns true every `REPORT_INTERVAL`,
+    /// when `stats` has a fresh frame rate and is worth showing.
+    pub fn update(&mut self, device: &wgpu::Device, capacity: u32) -> bool {
+        // natively, mapping only makes progress when the device is polled
+        device.poll(wgpu::Maintain::Poll);
+
+        for slot in &mut self.slots {
+            let (frame, passes, result) = match &mut slot.state {
+                SlotState::Mapping {
+                    frame,
+                    passes,
+                    mapped,
+                } => match mapped.as_mut().now_or_never() {
+                    Some(result) => (*frame, std::mem::take(passes), result),
+                    None => continue,
+                },
+                _ => continue,
+            };
+
+            if result.is_ok() {
+                if frame > self.latest_frame {
+                    let view = slot.buffer.slice(..).get_mapped_range();
+                    let (draw_args, timestamps) =
+                        view.split_at(self.systems * DRAW_ARGS_SIZE as usize);
+
+                    let draw_args: &[DrawArgs] = bytemuck::cast_slice(draw_args);
+                    self.stats.alive = draw_args.iter().map(|args| args.instance_count).sum();
+
+                    if !passes.is_empty() {
+                        let timestamps: Vec<u64> = timestamps
+                            .chunks_exact(TIMESTAMP_SIZE as usize)
+                            .take(passes.len() * 2)
+                            .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+                            .collect();
+                        let timings = PassTimings::new(&passes, &timestamps, self.timestamp_period);
+
+                        self.stats.physics_ms = timings.physics;
+                        self.stats.emission_ms = timings.emission;
+                        self.stats.render_ms = timings.render;
+
+                        #[cfg(feature = "tracy")]
+                        timings.plot();
+                    }
+
+                    self.latest_frame = frame;
+                }
+                slot.buffer.unmap();
+            }
+            slot.state = SlotState::Free;
+        }
+
+        self.stats.capacity = capacity;
+        self.frames_since_report += 1;
+
+        let now = self.stopwatch.seconds();
+        if now - self.last_report < REPORT_INTERVAL {
+            return false;
+        }
+
+        self.stats.fps = (self.frames_since_report as f64 / (now - self.last_report)) as f32;
+        self.frames_since_report = 0;
+        self.last_report = now;
+
+        #[cfg(target_arch = "wasm32")]
+        LATEST.with(|latest| latest.set(self.stats));
+
+        true
+    }
+}
@@ -0,0 +1,51 @@
+//! The sprite every particle is drawn with.
+
+use std::fmt;
+use std::path::Path;
+
+/// A single channel image for the fragment shader to read coverage from. Any image format the `image` crate
+/// decodes will do, it's converted to 8 bit luma.
+#[derive(Clone)]
+pub struct ParticleTexture {
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) pixels: Vec<u8>,
+}
+
+impl ParticleTexture {
+    /// The soft dot that's built into the binary.
+    pub fn built_in() -> Self {
+        Self::decode(include_bytes!("particle.png")).expect("built-in particle texture is invalid")
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, image::ImageError> {
+        let image = image::load_from_memory(bytes)?.to_luma8();
+        Ok(ParticleTexture {
+            width: image.width(),
+            height: image.height(),
+            pixels: image.into_raw(),
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, image::ImageError> {
+        let image = image::open(path)?.to_luma8();
+        Ok(ParticleTexture {
+            width: image.width(),
+            height: image.height(),
+            pixels: image.into_raw(),
+        })
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+}
+
+impl fmt::Debug for ParticleTexture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ParticleTexture")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .finish()
+    }
+}
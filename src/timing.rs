@@ -0,0 +1,153 @@
+/// Seconds since creation. `std::time::Instant` panics on the web, so there we ask the browser.
+pub struct Stopwatch {
+    #[cfg(not(target_arch = "wasm32"))]
+    start: std::time::Instant,
+    #[cfg(target_arch = "wasm32")]
+    start: f64,
+}
+
+impl Stopwatch {
+    pub fn new() -> Self {
+        Stopwatch {
+            #[cfg(not(target_arch = "wasm32"))]
+            start: std::time::Instant::now(),
+            #[cfg(target_arch = "wasm32")]
+            start: performance_now(),
+        }
+    }
+
+    pub fn seconds(&self) -> f64 {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            self.start.elapsed().as_secs_f64()
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        {
+            (performance_now() - self.start) / 1000.0
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn performance_now() -> f64 {
+    web_sys::window()
+        .and_then(|win| win.performance())
+        .map(|performance| performance.now())
+        .unwrap_or(0.0)
+}
+
+/// Longest frame we'll simulate in one go. Anything slower (a breakpoint, a dragged window)
+/// gets clamped rather than flinging every particle across the screen.
+const MAX_FRAME_TIME: f64 = 0.1;
+
+/// Cap on fixed steps per frame, so a slow frame can't snowball into an even slower one.
+const MAX_STEPS_PER_FRAME: u32 = 8;
+
+/// Slowest and fastest the simulation can be made to run relative to the wall clock.
+pub const MIN_TIME_SCALE: f32 = 0.1;
+pub const MAX_TIME_SCALE: f32 = 4.0;
+
+/// How much simulation to run this frame.
+#[derive(Debug, Clone, Copy)]
+pub struct Tick {
+    /// Wall clock seconds covered by each step.
+    pub dt: f32,
+    /// What each step's `dt` is multiplied by in the shaders.
+    pub time_scale: f32,
+    /// Simulated seconds so far.
+    pub time: f32,
+    /// Number of physics + emission steps to run.
+    pub steps: u32,
+}
+
+/// Turns wall clock time into simulation steps, either one variable step per frame,
+/// or (with `fixed_dt`) as many fixed steps as fit, carrying the remainder to the next frame.
+///
+/// The time scale only stretches each step, never the number of them, so slow motion
+/// with a fixed timestep is still exactly repeatable.
+pub struct Clock {
+    stopwatch: Stopwatch,
+    last_frame: f64,
+    time: f64,
+    fixed_dt: Option<f64>,
+    accumulator: f64,
+    time_scale: f32,
+}
+
+impl Clock {
+    pub fn new(fixed_dt: Option<f64>) -> Self {
+        Clock {
+            stopwatch: Stopwatch::new(),
+            last_frame: 0.0,
+            time: 0.0,
+            fixed_dt,
+            accumulator: 0.0,
+            time_scale: 1.0,
+        }
+    }
+
+    pub fn time_scale(&self) -> f32 {
+        self.time_scale
+    }
+
+    /// Clamped to `MIN_TIME_SCALE..=MAX_TIME_SCALE`, returns what it ended up as.
+    pub fn set_time_scale(&mut self, time_scale: f32) -> f32 {
+        self.time_scale = time_scale.max(MIN_TIME_SCALE).min(MAX_TIME_SCALE);
+        self.time_scale
+    }
+
+    /// Call once per frame.
+    pub fn tick(&mut self) -> Tick {
+        let now = self.stopwatch.seconds();
+        let elapsed = (now - self.last_frame).min(MAX_FRAME_TIME);
+        self.last_frame = now;
+
+        let (dt, steps) = match self.fixed_dt {
+            None => (elapsed, 1),
+            Some(fixed_dt) => {
+                self.accumulator += elapsed;
+                let steps = ((self.accumulator / fixed_dt) as u32).min(MAX_STEPS_PER_FRAME);
+                self.accumulator = (self.accumulator - steps as f64 * fixed_dt).min(fixed_dt);
+                (fixed_dt, steps)
+            }
+        };
+
+        self.time += dt * self.time_scale as f64 * steps as f64;
+
+        Tick {
+            dt: dt as f32,
+            time_scale: self.time_scale,
+            time: self.time as f32,
+            steps,
+        }
+    }
+
+    /// Like `tick`, but for when the simulation is frozen: time still passes on the wall clock,
+    /// it just doesn't count, and nothing builds up to be simulated once we resume.
+    pub fn tick_paused(&mut self) -> Tick {
+        self.last_frame = self.stopwatch.seconds();
+        self.accumulator = 0.0;
+
+        Tick {
+            dt: self.step_dt() as f32,
+            time_scale: self.time_scale,
+            time: self.time as f32,
+            steps: 0,
+        }
+    }
+
+    /// Exactly `steps` steps of the usual length, for stepping through things while paused.
+    pub fn step(&mut self, steps: u32) -> Tick {
+        let mut tick = self.tick_paused();
+        self.time += self.step_dt() * self.time_scale as f64 * steps as f64;
+
+        tick.time = self.time as f32;
+        tick.steps = steps;
+        tick
+    }
+
+    fn step_dt(&self) -> f64 {
+        self.fixed_dt.unwrap_or(1.0 / 60.0)
+    }
+}